@@ -0,0 +1,91 @@
+// Times compiling generated programs of doubling size with every pass on,
+// to show how compile time scales with the size of the source. Linear
+// scaling keeps the time per byte flat. Programs can't nest deeper than
+// `MAX_DEPTH`, so deep nests are repeated rather than made deeper.
+//
+//     cargo bench --bench compile_time
+
+use std::time::{Duration, Instant};
+
+use bf_wasm_compiler::backend::BackendOptions;
+use bf_wasm_compiler::frontend::{Dialect, ParseOptions};
+use bf_wasm_compiler::tree::MAX_DEPTH;
+use bf_wasm_compiler::{compile_optimized, optimize_with, CompileOptions};
+
+// Programs built from `n` repetitions of a pattern, each stressing a
+// different part of the pipeline, with their dialect and the largest `n`
+// to try.
+#[allow(clippy::type_complexity)]
+const SHAPES: [(&str, Dialect, fn(usize) -> String, usize); 5] = [
+    // Updates of distinct cells, which `combine` merges into one run.
+    ("straight", Dialect::Bf, |n| ">+".repeat(n), 1 << 20),
+    ("loops", Dialect::Bf, |n| "[->++<]>[>]<".repeat(n), 1 << 18),
+    // A single loop with a huge body, analyzed by every loop pass.
+    (
+        "wide loop",
+        Dialect::Bf,
+        |n| format!("+[{}{}-]", ">+".repeat(n), "<".repeat(n)),
+        1 << 18,
+    ),
+    // Loops nested as deep as they may be.
+    (
+        "nested",
+        Dialect::Bf,
+        |n| format!("{}{}", "+[>".repeat(MAX_DEPTH), "-]<".repeat(MAX_DEPTH)).repeat(n),
+        1 << 9,
+    ),
+    (
+        "procedures",
+        Dialect::Pbrain,
+        |n| "+(>[-]<)>+(:)".repeat(n),
+        1 << 18,
+    ),
+];
+
+fn time(source: &str, opts: &CompileOptions) -> Duration {
+    let start = Instant::now();
+    let optimized = optimize_with(source, opts, &mut |_, _| ()).unwrap();
+    compile_optimized(&optimized, opts);
+    start.elapsed()
+}
+
+fn main() {
+    let base = CompileOptions {
+        cell_zero_opt: true,
+        loop_opt: true,
+        licm: true,
+        scan_opt: true,
+        backend: BackendOptions {
+            reg_cache: true,
+            ..BackendOptions::default()
+        },
+        ..CompileOptions::default()
+    };
+    println!(
+        "{:<12} {:>10} {:>12} {:>10}",
+        "shape", "bytes", "time", "ns/byte"
+    );
+    for (name, dialect, program, max) in SHAPES {
+        let opts = CompileOptions {
+            parse: ParseOptions {
+                dialect,
+                ..ParseOptions::default()
+            },
+            ..base.clone()
+        };
+        let mut n = max >> 6;
+        while n <= max {
+            let source = program(n);
+            // The best of a few runs, to keep noise out of the small sizes.
+            let best = (0..3).map(|_| time(&source, &opts)).min().unwrap();
+            println!(
+                "{:<12} {:>10} {:>10.2}ms {:>10.1}",
+                name,
+                source.len(),
+                best.as_secs_f64() * 1e3,
+                best.as_nanos() as f64 / source.len() as f64
+            );
+            n *= 2;
+        }
+    }
+}
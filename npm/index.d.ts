@@ -0,0 +1,91 @@
+export type Dialect = 'bf' | 'ook' | 'blub' | 'alphuck' | 'pbrain' | 'extended'
+
+// Mirrors the flags of the CLI that don't name files. Everything is off by
+// default.
+export interface CompileOptions {
+  // The language the source is written in, `bf` by default.
+  dialect?: Dialect
+  // Make `#` dump the data pointer and the cells around it through `onDebug`.
+  debugHash?: boolean
+  // Treat the text after the first `!` outside a command as the program's
+  // input.
+  inlineInput?: boolean
+  cellZeroOpt?: boolean
+  loopOpt?: boolean
+  // Move stores that don't change between loop trips out of the loop.
+  licm?: boolean
+  scanOpt?: boolean
+  // Keep cells in wasm locals within straight-line code.
+  regCache?: boolean
+  // Run programs that never read input at compile time, with at most this
+  // many steps.
+  precompute?: number
+  // Stop the program after this many loop trips, setting `outOfFuel`.
+  fuel?: number | bigint
+  // Return from `main` whenever the program needs input. `run` resumes it.
+  // Input read inside pbrain procedures still goes through `env.read`.
+  resumable?: boolean
+  // Export the memory and `get_dp`, `get_cell` and `tape_len`.
+  inspect?: boolean
+  // Move loops of more than this many IR instructions into functions of
+  // their own.
+  outlineLoops?: number
+  // Keep the data pointer in a global instead of a local.
+  dpGlobal?: boolean
+}
+
+export interface RunOptions {
+  // Read byte by byte; once it runs out the program reads zero.
+  input?: string | Uint8Array
+  // Called with every byte the program writes.
+  onOutput?: (byte: number) => void
+  // Called at every `#` of programs built with `debugHash`, with the
+  // current cell and the tape, both counted from the cell the program
+  // starts on.
+  onDebug?: (cell: number, tape: Uint8Array) => void
+  // The budget of modules built with `--fuel param`, unlimited by default.
+  fuel?: number | bigint
+}
+
+export interface RunResult {
+  // Everything the program wrote.
+  output: Uint8Array
+  // The cell the program ended on and its value, or null if it didn't
+  // finish.
+  cell: number | null
+  value: number | null
+  outOfFuel: boolean
+  // The instance the program ran in, for the exports of `inspect` builds.
+  instance: WebAssembly.Instance
+}
+
+// Compiles a program to a wasm module importing `env.write`, `env.read` and
+// `env.debug_terminate`. Throws if the program's brackets don't match.
+export function compile(program: string, options?: CompileOptions): Uint8Array
+
+// The module `compile` builds, in the wasm text format.
+export function compileWat(program: string, options?: CompileOptions): string
+
+// The IR of a program before any pass, in its textual form.
+export function parsedIr(program: string, options?: CompileOptions): string
+
+export interface PassResult {
+  // `parse` for the IR as parsed, otherwise the pass that ran.
+  pass: 'parse' | 'combine' | 'zero' | 'loops' | 'licm' | 'scan' | 'unroll'
+  instructions: number
+  // How many instructions there are of each kind, named as in `ir`, not
+  // counting the ends of blocks.
+  counts: Record<string, number>
+  // The IR in its textual form.
+  ir: string
+}
+
+// The IR as parsed and after every pass the options enable, in the order
+// they ran.
+export function passResults(program: string, options?: CompileOptions): PassResult[]
+
+// Compiles and runs a program.
+export function run(program: string, options?: CompileOptions & RunOptions): Promise<RunResult>
+
+// Runs a module built by `compile` or the CLI.
+export function runWasm(wasm: BufferSource, options?: RunOptions): Promise<RunResult>
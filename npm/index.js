@@ -0,0 +1,133 @@
+const native = require('./pkg/bf_wasm_compiler.js')
+
+// Option names of `compile` and the fields of the native `CompileOptions`
+// they set.
+const FLAGS = {
+  debugHash: 'debug_hash',
+  inlineInput: 'inline_input',
+  cellZeroOpt: 'cell_zero_opt',
+  loopOpt: 'loop_opt',
+  licm: 'licm',
+  scanOpt: 'scan_opt',
+  regCache: 'reg_cache',
+  resumable: 'resumable',
+  inspect: 'inspect',
+  dpGlobal: 'dp_global',
+}
+
+// Status `main` and `resume` return in resumable builds.
+const NEEDS_INPUT = 1
+
+function nativeOptions(options = {}) {
+  const opts = new native.CompileOptions()
+  for (const [name, field] of Object.entries(FLAGS)) {
+    opts[field] = Boolean(options[name])
+  }
+  if (options.dialect !== undefined) {
+    opts.set_dialect(options.dialect)
+  }
+  if (options.precompute !== undefined) {
+    opts.set_precompute(options.precompute)
+  }
+  if (options.fuel !== undefined) {
+    opts.set_fuel(BigInt(options.fuel))
+  }
+  if (options.outlineLoops !== undefined) {
+    opts.set_outline_loops(options.outlineLoops)
+  }
+  return opts
+}
+
+// Calls `f` of the native module with `program` and the native form of
+// `options`.
+function withOptions(f, program, options) {
+  const opts = nativeOptions(options)
+  try {
+    return f(program, opts)
+  } finally {
+    opts.free()
+  }
+}
+
+function compile(program, options = {}) {
+  return withOptions(native.compile_with, program, options)
+}
+
+function compileWat(program, options = {}) {
+  return withOptions(native.compile_wat, program, options)
+}
+
+function parsedIr(program, options = {}) {
+  return withOptions(native.parsed_ir, program, options)
+}
+
+function passResults(program, options = {}) {
+  return JSON.parse(withOptions(native.pass_results, program, options))
+}
+
+function toBytes(input) {
+  if (input === undefined) {
+    return new Uint8Array(0)
+  }
+  return typeof input === 'string' ? new TextEncoder().encode(input) : input
+}
+
+// Runs a compiled module, with `read` taking bytes from `input` and then
+// zero, like the native runtime.
+async function runWasm(wasm, { input, onOutput, onDebug, fuel } = {}) {
+  const bytes = toBytes(input)
+  let read = 0
+  const output = []
+  const result = { output: null, cell: null, value: null, outOfFuel: false, instance: null }
+  let exports
+  const imports = {
+    env: {
+      write: byte => {
+        output.push(byte)
+        if (onOutput) {
+          onOutput(byte)
+        }
+      },
+      read: () => (read < bytes.length ? bytes[read++] : 0),
+      debug_terminate: (addr, value) => {
+        result.cell = addr - 16
+        result.value = value
+      },
+      out_of_fuel: () => {
+        result.outOfFuel = true
+      },
+      // Programs built with `debugHash` call this at every `#` with the
+      // address of the current cell.
+      debug: addr => {
+        if (onDebug) {
+          onDebug(addr - 16, new Uint8Array(exports.memory.buffer, 16, 65536 - 16))
+        }
+      },
+    },
+  }
+  const { instance } = await WebAssembly.instantiate(wasm, imports)
+  exports = instance.exports
+  const { main, resume } = exports
+  try {
+    // Modules built with `--fuel param` take their budget, which is
+    // unlimited unless given.
+    let status = main.length === 1 ? main(BigInt(fuel ?? '0x7fffffffffffffff')) : main()
+    while (resume && status === NEEDS_INPUT) {
+      status = resume(imports.env.read())
+    }
+  } catch (e) {
+    // Helpers trap once they ran out of fuel.
+    if (!(result.outOfFuel && e instanceof WebAssembly.RuntimeError)) {
+      throw e
+    }
+  }
+  result.output = Uint8Array.from(output)
+  result.instance = instance
+  return result
+}
+
+async function run(program, options = {}) {
+  return runWasm(compile(program, options), options)
+}
+
+module.exports = { compile, compileWat, parsedIr, passResults, run, runWasm }
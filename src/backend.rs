@@ -1,50 +1,297 @@
 use wasm_encoder::{
-    BlockType, CodeSection, ExportKind, ExportSection, Function, FunctionSection, ImportSection,
-    Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+    BlockType, CodeSection, ConstExpr, DataSection, ElementSection, Elements, ExportKind,
+    ExportSection, Function, FunctionSection, GlobalSection, GlobalType, ImportSection,
+    Instruction, MemArg, MemorySection, MemoryType, Module, RefType, TableSection, TableType,
+    TypeSection, ValType,
 };
 
-use crate::ir::{Inst, IR};
+use crate::interp::{DP_START, TAPE_LEN};
+use crate::ir::{BitOp, Inst, IR};
+use frame::{Dp, Frame};
+use regcache::{RegCache, CACHE_SIZE};
+use resume::{ResumeGlobals, Resumer, FINISHED, OUT_OF_FUEL};
 
-const DP: u32 = 0;
+mod frame;
+mod regcache;
+pub mod resume;
 
-pub fn create_wasm(ir: &IR) -> Vec<u8> {
-    let mut module = Module::new();
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    // Keep cells in locals within straight-line code.
+    pub reg_cache: bool,
+    // Count how often each profile site runs.
+    pub profile: bool,
+    // Limit the number of loop trips the program may take.
+    pub fuel: Option<Fuel>,
+    // Return from `main` when the program needs input, to be continued by
+    // calling `resume` with the next byte. Procedures can't suspend, so
+    // `In` inside one still blocks on `env.read`.
+    pub resumable: bool,
+    // Export the memory and `get_dp`, `get_cell` and `tape_len` to look at
+    // the tape once `main` returns.
+    pub inspect: bool,
+    // Input embedded in the module, which `In` reads in place of calling
+    // `env.read`. Once it runs out `In` stores zero.
+    pub input: Option<Vec<u8>>,
+    // Move loops of more than this many instructions into functions of
+    // their own, as engines optimize huge functions poorly if at all.
+    pub outline: Option<usize>,
+    // Keep the data pointer in a global all functions share rather than in
+    // a local passed to helpers and back. Slower, but the host can see the
+    // pointer at any time and helpers take no arguments.
+    pub dp_global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuelBudget {
+    Fixed(u64),
+    // `main` takes the budget as an i64 parameter.
+    Param,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfFuel {
+    Trap,
+    // Call `env.out_of_fuel` and return from `main`, with `OUT_OF_FUEL` in
+    // resumable builds.
+    Import,
+}
+
+// Fuel is burnt at every loop back-edge. The remaining fuel is exported as
+// the mutable i64 global `fuel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fuel {
+    pub budget: FuelBudget,
+    pub out_of_fuel: OutOfFuel,
+}
+
+// The profile table lives in the pages after the tape. It starts with the
+// number of sites as an i32, padded to `PROFILE_ENTRY` bytes, followed by
+// an entry per site holding two i64 counters: how often the site was
+// reached and, for loops, how often the body ran.
+pub const PROFILE_BASE: u32 = 65536;
+pub const PROFILE_ENTRY: u32 = 16;
+
+// Type indices line up with function indices for the imports, since each
+// import gets the type of the same index.
+const JS_WRITE: u32 = 0;
+const JS_READ: u32 = 1;
+const JS_DEBUG_TERMINATE: u32 = 2;
+const VOID_TYPE: u32 = 3;
+const BUDGET_TYPE: u32 = 4;
+const BUDGET_STATUS_TYPE: u32 = 5;
+const RESUME_TYPE: u32 = 6;
+
+const FUEL: u32 = 0;
+
+// Function indices that depend on the options a module is built with.
+// Imports come first, followed by `main`, the program body when it isn't
+// `main` itself, `resume`, `dump_profile`, the inspection functions and
+// the helper functions holding parts of the program.
+struct Layout {
+    out_of_fuel: Option<u32>,
+    // `env.debug`, imported when the program has `Inst::Debug`.
+    debug: Option<u32>,
+    main: u32,
+    body: u32,
+    resume: Option<u32>,
+    dump_profile: Option<u32>,
+    // `get_dp`, `get_cell` and `tape_len`, in that order.
+    inspect: Option<u32>,
+    // The first helper, see `Helpers`, and how many there are.
+    helpers: u32,
+    helper_count: u32,
+    // Starts of the loops moved into helpers, in order.
+    outlined: Vec<usize>,
+    // Global `DP` is saved to whenever the program returns, or kept in all
+    // along with `dp_global`. It comes after `FUEL`, followed by the rest of
+    // the state of resumable builds.
+    dp: Option<u32>,
+    // Where functions keep `DP`: local 0, which helpers take as their
+    // parameter, or the global above.
+    pointer: Dp,
+    // Helpers take and return `DP` unless it is a global.
+    helper_type: u32,
+    resume_globals: Option<ResumeGlobals>,
+    // How much of the embedded input has been read, after the other globals.
+    input_read: Option<u32>,
+    // The storage cell of Extended Brainfuck, after the other globals.
+    storage: Option<u32>,
+    // Memory holds the tape, then the profile table and the embedded input,
+    // each starting on a page of its own.
+    pages: u64,
+    input_base: u32,
+}
+
+impl Layout {
+    fn new(opts: &BackendOptions, ir: &IR) -> Layout {
+        let mut next = JS_DEBUG_TERMINATE + 1;
+        let mut take = |present: bool| {
+            next += present as u32;
+            present.then_some(next - 1)
+        };
+        let fuel = opts.fuel;
+        let out_of_fuel = take(fuel.is_some_and(|fuel| fuel.out_of_fuel == OutOfFuel::Import));
+        let debug = take(ir.contains(&Inst::Debug));
+        let main = take(true).unwrap();
+        let body =
+            take(opts.resumable || fuel.is_some_and(|fuel| fuel.budget == FuelBudget::Param));
+        let resume = take(opts.resumable);
+        let dump_profile = take(opts.profile);
+        let inspect = opts.inspect.then(|| {
+            next += 3;
+            next - 3
+        });
+        let procs = ir.iter().filter(|ins| **ins == Inst::ProcStart).count() as u32;
+        let outlined = match opts.outline {
+            Some(size) => outlined_loops(ir, size, opts.resumable),
+            None => vec![],
+        };
+        let dp =
+            (opts.resumable || opts.inspect || opts.dp_global).then_some(fuel.is_some() as u32);
+        let pointer = match dp.filter(|_| opts.dp_global) {
+            Some(dp) => Dp::Global(dp),
+            None => Dp::Local(0),
+        };
+        let globals = fuel.is_some() as u32 + dp.is_some() as u32 + 3 * opts.resumable as u32;
+        let input_read = opts.input.is_some().then_some(globals);
+        let uses_storage = ir.iter().any(|ins| {
+            matches!(
+                ins,
+                Inst::Save | Inst::Restore | Inst::Bitwise(BitOp::Xor | BitOp::And | BitOp::Or)
+            )
+        });
+        let storage = uses_storage.then_some(globals + input_read.is_some() as u32);
+        let sites = ir.iter().filter(|ins| is_profile_site(ins)).count() as u32;
+        let mut pages = 1;
+        if opts.profile {
+            pages += ((sites + 1) * PROFILE_ENTRY).div_ceil(65536) as u64;
+        }
+        let input_base = pages as u32 * 65536;
+        if let Some(input) = &opts.input {
+            pages += (input.len() as u64).div_ceil(65536);
+        }
+        Layout {
+            out_of_fuel,
+            debug,
+            main,
+            body: body.unwrap_or(main),
+            resume,
+            dump_profile,
+            inspect,
+            helpers: next,
+            helper_count: procs + outlined.len() as u32,
+            outlined,
+            dp,
+            pointer,
+            helper_type: match pointer {
+                Dp::Local(_) => RESUME_TYPE,
+                Dp::Global(_) => VOID_TYPE,
+            },
+            resume_globals: opts
+                .resumable
+                .then(|| ResumeGlobals::new(fuel.is_some() as u32)),
+            input_read,
+            storage,
+            pages,
+            input_base,
+        }
+    }
+}
+
+fn encode_header(module: &mut Module, memory_pages: u64, opts: &BackendOptions, ir: &IR) {
+    let layout = Layout::new(opts, ir);
 
     // Encode the type section.
     let mut types = TypeSection::new();
-
     types.function([ValType::I32], []);
-    let js_write = types.len() - 1;
-
     types.function([], [ValType::I32]);
-    let js_read = types.len() - 1;
-
     types.function([ValType::I32, ValType::I32], []);
-    let js_debug_terminate = types.len() - 1;
-
     types.function([], []);
-    let bf_main = types.len() - 1;
-
+    types.function([ValType::I64], []);
+    types.function([ValType::I64], [ValType::I32]);
+    types.function([ValType::I32], [ValType::I32]);
     module.section(&types);
 
     let mut imports = ImportSection::new();
-    imports.import("env", "write", wasm_encoder::EntityType::Function(js_write));
-    imports.import("env", "read", wasm_encoder::EntityType::Function(js_read));
+    imports.import("env", "write", wasm_encoder::EntityType::Function(JS_WRITE));
+    imports.import("env", "read", wasm_encoder::EntityType::Function(JS_READ));
     imports.import(
         "env",
         "debug_terminate",
-        wasm_encoder::EntityType::Function(js_debug_terminate),
+        wasm_encoder::EntityType::Function(JS_DEBUG_TERMINATE),
     );
+    if layout.out_of_fuel.is_some() {
+        imports.import(
+            "env",
+            "out_of_fuel",
+            wasm_encoder::EntityType::Function(VOID_TYPE),
+        );
+    }
+    // `debug` takes the address of the current cell, like `write` takes a
+    // value.
+    if layout.debug.is_some() {
+        imports.import("env", "debug", wasm_encoder::EntityType::Function(JS_WRITE));
+    }
     module.section(&imports);
 
     // Encode the function section.
+    // Resumable builds return their status, which has the type of `read`.
+    let budget = opts
+        .fuel
+        .is_some_and(|fuel| fuel.budget == FuelBudget::Param);
     let mut functions = FunctionSection::new();
-    functions.function(bf_main);
+    match (budget, opts.resumable) {
+        (false, false) => functions.function(VOID_TYPE),
+        (true, false) => functions.function(BUDGET_TYPE),
+        (false, true) => functions.function(JS_READ),
+        (true, true) => functions.function(BUDGET_STATUS_TYPE),
+    };
+    if layout.body != layout.main {
+        match opts.resumable {
+            true => functions.function(JS_READ),
+            false => functions.function(VOID_TYPE),
+        };
+    }
+    if layout.resume.is_some() {
+        functions.function(RESUME_TYPE);
+    }
+    // `dump_profile` returns the address of the profile table, which is the
+    // same signature as `read`.
+    if layout.dump_profile.is_some() {
+        functions.function(JS_READ);
+    }
+    // `get_cell` has the signature of `resume`.
+    if layout.inspect.is_some() {
+        functions.function(JS_READ);
+        functions.function(RESUME_TYPE);
+        functions.function(JS_READ);
+    }
+    for _ in 0..layout.helper_count {
+        functions.function(layout.helper_type);
+    }
     module.section(&functions);
 
+    // Procedures are called through a table indexed by the cell value that
+    // calls them, which `ProcStart` fills in.
+    if ir
+        .iter()
+        .any(|ins| matches!(ins, Inst::ProcStart | Inst::Call))
+    {
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: 256,
+            maximum: Some(256),
+            shared: false,
+        });
+        module.section(&tables);
+    }
+
     let mut memories = MemorySection::new();
     memories.memory(MemoryType {
-        minimum: 1,
+        minimum: memory_pages,
         maximum: None,
         memory64: false,
         shared: false,
@@ -52,54 +299,514 @@ pub fn create_wasm(ir: &IR) -> Vec<u8> {
     });
     module.section(&memories);
 
+    let mut globals = GlobalSection::new();
+    if let Some(fuel) = &opts.fuel {
+        let budget = match fuel.budget {
+            FuelBudget::Fixed(budget) => budget as i64,
+            FuelBudget::Param => 0,
+        };
+        globals.global(
+            GlobalType {
+                val_type: ValType::I64,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i64_const(budget),
+        );
+    }
+    // The saved `DP`, followed by the rest of the state of resumable builds.
+    let i32_globals = match opts.resumable {
+        true => 4,
+        false => layout.dp.is_some() as usize,
+    } + layout.input_read.is_some() as usize
+        + layout.storage.is_some() as usize;
+    for _ in 0..i32_globals {
+        globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i32_const(0),
+        );
+    }
+    if !globals.is_empty() {
+        module.section(&globals);
+    }
+
     // Encode the export section.
     let mut exports = ExportSection::new();
-    exports.export("main", ExportKind::Func, bf_main);
+    exports.export("main", ExportKind::Func, layout.main);
+    if let Some(resume) = layout.resume {
+        exports.export("resume", ExportKind::Func, resume);
+    }
+    if let Some(dump_profile) = layout.dump_profile {
+        exports.export("dump_profile", ExportKind::Func, dump_profile);
+    }
+    if let Some(inspect) = layout.inspect {
+        exports.export("get_dp", ExportKind::Func, inspect);
+        exports.export("get_cell", ExportKind::Func, inspect + 1);
+        exports.export("tape_len", ExportKind::Func, inspect + 2);
+    }
+    if opts.profile || opts.inspect || layout.debug.is_some() {
+        exports.export("memory", ExportKind::Memory, 0);
+    }
+    if opts.fuel.is_some() {
+        exports.export("fuel", ExportKind::Global, FUEL);
+    }
     module.section(&exports);
 
+    // `ref.func` may only name functions declared in an element segment.
+    if layout.helper_count > 0 {
+        let helpers: Vec<u32> = (layout.helpers..layout.helpers + layout.helper_count).collect();
+        let mut elements = ElementSection::new();
+        elements.declared(Elements::Functions(&helpers));
+        module.section(&elements);
+    }
+}
+
+// Loops of more than `size` instructions, which get a helper each, nested
+// loops included. Loops that end the program are left in place, as a helper
+// can only return to its caller, and so are loops a resumable build may
+// suspend in.
+fn outlined_loops(ir: &IR, size: usize, resumable: bool) -> Vec<usize> {
+    let mut outlined = vec![];
+    // The start of each open loop and whether it has to stay in place,
+    // which then goes for the loops around it as well.
+    let mut open: Vec<(usize, bool)> = vec![];
+    for (idx, ins) in ir.iter().enumerate() {
+        match ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) => open.push((idx, false)),
+            Inst::LoopEnd | Inst::SimpleLoopEnd => {
+                let (start, pinned) = open.pop().unwrap();
+                match open.last_mut() {
+                    Some(outer) if pinned => outer.1 = true,
+                    _ => (),
+                }
+                if !pinned && idx - start - 1 > size {
+                    outlined.push(start);
+                }
+            }
+            Inst::In if !resumable => (),
+            Inst::Exit | Inst::In => {
+                if let Some(innermost) = open.last_mut() {
+                    innermost.1 = true;
+                }
+            }
+            _ => (),
+        }
+    }
+    outlined.sort();
+    outlined
+}
+
+fn validate(wasm_bytes: &[u8]) {
+    match wasmparser::validate(wasm_bytes) {
+        Ok(_) => (),
+        Err(e) => panic!("\n\nERROR:\n{}\n", e),
+    }
+}
+
+// Instructions that get a profile counter, in the order of their entries in
+// the profile table.
+pub fn is_profile_site(ins: &Inst) -> bool {
+    matches!(
+        ins,
+        Inst::LoopStart | Inst::SimpleLoopStart(_) | Inst::Out | Inst::In
+    )
+}
+
+pub fn create_wasm(ir: &IR, opts: &BackendOptions) -> Vec<u8> {
+    let mut module = Module::new();
+    let sites = ir.iter().filter(|ins| is_profile_site(ins)).count() as u32;
+    let layout = Layout::new(opts, ir);
+    encode_header(&mut module, layout.pages, opts, ir);
+
     // Encode the code section.
     let mut codes = CodeSection::new();
-    let locals = vec![(1, ValType::I32), (1, ValType::I32)];
-    let mut f = Function::new(locals);
+    // The scratch register and the cache, after `DP` if it is a local.
+    // Helpers get that as their parameter, so they declare every local but
+    // it.
+    let mut locals = vec![(1, ValType::I32)];
+    let mut cache = None;
+    if opts.reg_cache {
+        locals.push((CACHE_SIZE, ValType::I32));
+        cache = Some(RegCache::new(layout.pointer.first_free_local() + 1));
+    }
+    let mut f = match layout.pointer {
+        Dp::Local(_) => Frame::new([&[(1, ValType::I32)], &locals[..]].concat(), layout.pointer),
+        Dp::Global(_) => Frame::new(locals.clone(), layout.pointer),
+    };
+    let mut helpers = Helpers::new(layout.helpers, locals, layout.pointer);
+    // Whether each open loop was outlined.
+    let mut open_loops = vec![];
 
-    f.instruction(&Instruction::I32Const(16));
-    f.instruction(&Instruction::LocalSet(DP));
+    let mut resumer = layout
+        .resume_globals
+        .map(|globals| Resumer::new(ir, globals));
+    match &mut resumer {
+        Some(resumer) => resumer.start(&mut f),
+        None => {
+            f.instruction(&Instruction::I32Const(16));
+            f.set_dp();
+        }
+    }
 
-    for ins in ir {
-        match ins {
-            Inst::Add(ct) => add(&mut f, *ct),
-            Inst::Sub(ct) => sub(&mut f, *ct),
-            Inst::AddFrom(ct, off) => add_from(&mut f, *ct, *off),
-            Inst::SubFrom(ct, off) => sub_from(&mut f, *ct, *off),
-            Inst::Right(ct) => dp_r(&mut f, *ct),
-            Inst::Left(ct) => dp_l(&mut f, *ct),
-            Inst::LoopStart => loop_start(&mut f),
-            Inst::LoopEnd => loop_end(&mut f),
-            Inst::Zero(off) => set_0(&mut f, *off),
-            Inst::Out => print(&mut f, js_write),
-            Inst::In => read(&mut f, js_read),
-            Inst::SimpleLoopStart(off) => simple_loop_start(&mut f, *off),
-            Inst::SimpleLoopEnd => simple_loop_end(&mut f),
-            Inst::Scan(stride) => scan(&mut f, *stride),
+    let mut site = 0;
+    if opts.profile {
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I32Const(sites as i32));
+        f.instruction(&Instruction::I32Store(MemArg {
+            offset: PROFILE_BASE as u64,
+            align: 2,
+            memory_index: 0,
+        }));
+    }
+
+    for (idx, ins) in ir.iter().enumerate() {
+        let in_helper = helpers.building();
+        // Counting before the entry point keeps a resumed run from counting
+        // the site again.
+        if opts.profile && is_profile_site(ins) {
+            count(&mut f, site, 0);
+            site += 1;
+        }
+        if let Some(resumer) = resumer.as_mut().filter(|resumer| resumer.is_entry(idx)) {
+            if let Some(cache) = &mut cache {
+                cache.flush(&mut f);
+            }
+            resumer.enter(&mut f);
+        }
+        if let Some(cache) = &mut cache {
+            // The cache only reads input through `env.read`.
+            let reads_import = (resumer.is_none() || in_helper) && opts.input.is_none();
+            if (reads_import || *ins != Inst::In) && cache.lower(&mut f, ins) {
+                continue;
+            }
+            cache.flush(&mut f);
+        }
+        if matches!(ins, Inst::LoopStart | Inst::SimpleLoopStart(_)) {
+            let outlined = layout.outlined.binary_search(&idx).is_ok();
+            if outlined {
+                call_helper(&mut f, helpers.next());
+                helpers.start(&mut f);
+            }
+            open_loops.push(outlined);
+        }
+        match (ins, &mut resumer) {
+            (Inst::LoopStart, Some(resumer)) if resumer.holds_point(idx) => {
+                resumer.loop_start(&mut f, idx)
+            }
+            (Inst::SimpleLoopStart(off), Some(resumer)) if resumer.holds_point(idx) => {
+                resumer.simple_loop_start(&mut f, idx, *off)
+            }
+            (Inst::In, Some(resumer)) if opts.input.is_none() && !in_helper => {
+                resumer.read(&mut f, idx)
+            }
+            (Inst::ProcStart, _) => {
+                define_proc(&mut f, helpers.next());
+                helpers.start(&mut f);
+            }
+            (Inst::ProcEnd, _) => helpers.end(&mut f),
+            _ => lower(&mut f, ins, opts, &layout, in_helper),
+        }
+        if opts.profile && matches!(ins, Inst::LoopStart | Inst::SimpleLoopStart(_)) {
+            count(&mut f, site - 1, 1);
+        }
+        if matches!(ins, Inst::LoopEnd | Inst::SimpleLoopEnd) && open_loops.pop().unwrap() {
+            helpers.end(&mut f);
+        }
+    }
+    if let Some(cache) = &mut cache {
+        cache.flush(&mut f);
+    }
+
+    finish(&mut f, opts, &layout);
+    f.instruction(&Instruction::End);
+    if layout.body != layout.main {
+        let mut main = Function::new(vec![]);
+        if opts
+            .fuel
+            .is_some_and(|fuel| fuel.budget == FuelBudget::Param)
+        {
+            main.instruction(&Instruction::LocalGet(0));
+            main.instruction(&Instruction::GlobalSet(FUEL));
+        }
+        if let Some(globals) = layout.resume_globals {
+            main.instruction(&Instruction::I32Const(0));
+            main.instruction(&Instruction::GlobalSet(globals.resuming));
+        }
+        main.instruction(&Instruction::Call(layout.body));
+        main.instruction(&Instruction::End);
+        codes.function(&main);
+    }
+    codes.function(&f.into_function());
+    if let Some(globals) = layout.resume_globals {
+        let mut resume = Function::new(vec![]);
+        resume.instruction(&Instruction::LocalGet(0));
+        resume.instruction(&Instruction::GlobalSet(globals.input));
+        resume.instruction(&Instruction::I32Const(1));
+        resume.instruction(&Instruction::GlobalSet(globals.resuming));
+        resume.instruction(&Instruction::Call(layout.body));
+        resume.instruction(&Instruction::End);
+        codes.function(&resume);
+    }
+    if opts.profile {
+        let mut dump = Function::new(vec![]);
+        dump.instruction(&Instruction::I32Const(PROFILE_BASE as i32));
+        dump.instruction(&Instruction::End);
+        codes.function(&dump);
+    }
+    if let Some(dp) = layout.dp.filter(|_| opts.inspect) {
+        add_inspection(&mut codes, dp);
+    }
+    for helper in helpers.finish() {
+        codes.function(&helper);
+    }
+    module.section(&codes);
+
+    if let Some(input) = &opts.input {
+        let mut data = DataSection::new();
+        let base = ConstExpr::i32_const(layout.input_base as i32);
+        data.active(0, &base, input.iter().copied());
+        module.section(&data);
+    }
+
+    let wasm_bytes = module.finish();
+    validate(&wasm_bytes);
+
+    wasm_bytes
+}
+
+// Functions holding the parts of the program lowered outside of its body:
+// a function per procedure and per outlined loop. A helper is started where
+// its code begins in the IR, which may be inside another.
+struct Helpers {
+    first: u32,
+    locals: Vec<(u32, ValType)>,
+    pointer: Dp,
+    // Finished helpers by number, with `None` for the ones being built.
+    done: Vec<Option<Function>>,
+    // The functions the helpers being built were started from, innermost
+    // last, with the number of the helper.
+    callers: Vec<(Frame, usize)>,
+}
+
+impl Helpers {
+    fn new(first: u32, locals: Vec<(u32, ValType)>, pointer: Dp) -> Helpers {
+        Helpers {
+            first,
+            locals,
+            pointer,
+            done: vec![],
+            callers: vec![],
         }
     }
 
-    add_debug_termination(&mut f, js_debug_terminate);
+    // Function index of the helper `start` begins next.
+    fn next(&self) -> u32 {
+        self.first + self.done.len() as u32
+    }
+
+    fn building(&self) -> bool {
+        !self.callers.is_empty()
+    }
+
+    // Switches `f` to a new helper.
+    fn start(&mut self, f: &mut Frame) {
+        let helper = Frame::new(self.locals.clone(), self.pointer);
+        self.callers
+            .push((std::mem::replace(f, helper), self.done.len()));
+        self.done.push(None);
+    }
+
+    // Returns from the helper in `f` and switches back to its caller.
+    fn end(&mut self, f: &mut Frame) {
+        f.pass_dp();
+        f.instruction(&Instruction::End);
+        let (caller, helper) = self.callers.pop().unwrap();
+        self.done[helper] = Some(std::mem::replace(f, caller).into_function());
+    }
+
+    fn finish(self) -> impl Iterator<Item = Function> {
+        self.done.into_iter().map(|helper| helper.unwrap())
+    }
+}
+
+// Cells are numbered from `DP_START`, the cell the program starts at, as in
+// the runner's report of `debug_terminate`.
+fn add_inspection(codes: &mut CodeSection, dp: u32) {
+    let mut get_dp = Function::new(vec![]);
+    get_dp.instruction(&Instruction::GlobalGet(dp));
+    get_dp.instruction(&Instruction::I32Const(DP_START as i32));
+    get_dp.instruction(&Instruction::I32Sub);
+    get_dp.instruction(&Instruction::End);
+    codes.function(&get_dp);
+
+    let mut get_cell = Function::new(vec![]);
+    get_cell.instruction(&Instruction::LocalGet(0));
+    get_cell.instruction(&Instruction::I32Load8U(MemArg {
+        offset: DP_START as u64,
+        ..null_mem_arg()
+    }));
+    get_cell.instruction(&Instruction::End);
+    codes.function(&get_cell);
+
+    let mut tape_len = Function::new(vec![]);
+    tape_len.instruction(&Instruction::I32Const((TAPE_LEN - DP_START) as i32));
+    tape_len.instruction(&Instruction::End);
+    codes.function(&tape_len);
+}
+
+// Builds a module for a program whose output was computed ahead of time.
+// The output is stored in a data segment and written out byte by byte, then
+// `debug_terminate` reports the final cell just like a full run would.
+pub fn create_output_wasm(output: &[u8], dp: usize, cell: u8) -> Vec<u8> {
+    let mut module = Module::new();
+    let pages = (output.len() as u64).div_ceil(65536).max(1);
+    encode_header(&mut module, pages, &BackendOptions::default(), &IR::new());
+
+    let mut codes = CodeSection::new();
+    let mut f = Function::new(vec![(1, ValType::I32)]);
+    let idx = 0;
+
+    f.instruction(&Instruction::Block(BlockType::Empty));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(idx));
+    f.instruction(&Instruction::I32Const(output.len() as i32));
+    f.instruction(&Instruction::I32GeU);
+    f.instruction(&Instruction::BrIf(1));
+    f.instruction(&Instruction::LocalGet(idx));
+    f.instruction(&Instruction::I32Load8U(null_mem_arg()));
+    f.instruction(&Instruction::Call(JS_WRITE));
+    f.instruction(&Instruction::LocalGet(idx));
+    f.instruction(&Instruction::I32Const(1));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalSet(idx));
+    f.instruction(&Instruction::Br(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+
+    f.instruction(&Instruction::I32Const(dp as i32));
+    f.instruction(&Instruction::I32Const(cell as i32));
+    f.instruction(&Instruction::Call(JS_DEBUG_TERMINATE));
 
     f.instruction(&Instruction::End);
     codes.function(&f);
     module.section(&codes);
 
+    let mut data = DataSection::new();
+    data.active(0, &ConstExpr::i32_const(0), output.iter().copied());
+    module.section(&data);
+
     let wasm_bytes = module.finish();
-    match wasmparser::validate(&wasm_bytes) {
-        Ok(_) => (),
-        Err(e) => {
-            println!("\n\nERROR:\n{}\n", e);
-            assert!(false);
+    validate(&wasm_bytes);
+
+    wasm_bytes
+}
+
+// `in_helper` is set for code in a helper, which can't return from `main`.
+fn lower(f: &mut Frame, ins: &Inst, opts: &BackendOptions, layout: &Layout, in_helper: bool) {
+    match ins {
+        Inst::Add(d, off) => add(f, *d, *off),
+        Inst::AddFrom(ct, off) => add_from(f, *ct, *off),
+        Inst::SubFrom(ct, off) => sub_from(f, *ct, *off),
+        Inst::Right(ct) => dp_r(f, *ct),
+        Inst::Left(ct) => dp_l(f, *ct),
+        Inst::LoopStart => loop_start(f),
+        Inst::LoopEnd => {
+            if let Some(fuel) = &opts.fuel {
+                burn_fuel(f, fuel, layout, in_helper);
+            }
+            loop_end(f)
+        }
+        Inst::Set(v, off) => set(f, *v, *off),
+        Inst::Out => print(f, JS_WRITE),
+        Inst::In => match (layout.input_read, &opts.input) {
+            (Some(input_read), Some(input)) => {
+                read_embedded(f, input_read, layout.input_base, input.len() as u32)
+            }
+            _ => read(f, JS_READ),
+        },
+        Inst::SimpleLoopStart(off) => simple_loop_start(f, *off),
+        Inst::SimpleLoopEnd => simple_loop_end(f),
+        Inst::Scan(stride) => scan(f, *stride),
+        Inst::Debug => {
+            f.get_dp();
+            f.instruction(&Instruction::Call(layout.debug.unwrap()));
+        }
+        // An undefined procedure has a null entry, which traps.
+        Inst::Call => {
+            f.pass_dp();
+            f.get_dp();
+            f.instruction(&Instruction::I32Load8U(null_mem_arg()));
+            f.instruction(&Instruction::CallIndirect {
+                type_index: layout.helper_type,
+                table_index: 0,
+            });
+            f.take_dp();
         }
+        Inst::Exit => {
+            finish(f, opts, layout);
+            f.instruction(&Instruction::Return);
+        }
+        Inst::Save => {
+            f.get_dp();
+            f.instruction(&Instruction::I32Load8U(null_mem_arg()));
+            f.instruction(&Instruction::GlobalSet(layout.storage.unwrap()));
+        }
+        Inst::Restore => {
+            f.get_dp();
+            f.instruction(&Instruction::GlobalGet(layout.storage.unwrap()));
+            f.instruction(&Instruction::I32Store8(null_mem_arg()));
+        }
+        Inst::Bitwise(op) => bitwise(f, *op, layout.storage),
+        Inst::ProcStart | Inst::ProcEnd => unreachable!("{:?} is lowered by create_wasm", ins),
     }
+}
 
-    wasm_bytes
+// Ends the program, leaving the state the host looks at on return.
+fn finish(f: &mut Frame, opts: &BackendOptions, layout: &Layout) {
+    save_dp(f, layout);
+    add_debug_termination(f, JS_DEBUG_TERMINATE);
+    if opts.resumable {
+        f.instruction(&Instruction::I32Const(FINISHED));
+    }
+}
+
+fn call_helper(f: &mut Frame, helper: u32) {
+    f.pass_dp();
+    f.instruction(&Instruction::Call(helper));
+    f.take_dp();
+}
+
+// Makes the current cell number the procedure in function `proc`.
+fn define_proc(f: &mut Frame, proc: u32) {
+    f.get_dp();
+    f.instruction(&Instruction::I32Load8U(null_mem_arg()));
+    f.instruction(&Instruction::RefFunc(proc));
+    f.instruction(&Instruction::TableSet(0));
+}
+
+fn bitwise(f: &mut Frame, op: BitOp, storage: Option<u32>) {
+    f.get_dp();
+    f.get_dp();
+    f.instruction(&Instruction::I32Load8U(null_mem_arg()));
+    match op {
+        BitOp::Shl | BitOp::Shr => f.instruction(&Instruction::I32Const(1)),
+        BitOp::Not => f.instruction(&Instruction::I32Const(-1)),
+        BitOp::Xor | BitOp::And | BitOp::Or => {
+            f.instruction(&Instruction::GlobalGet(storage.unwrap()))
+        }
+    };
+    f.instruction(match op {
+        BitOp::Shl => &Instruction::I32Shl,
+        BitOp::Shr => &Instruction::I32ShrU,
+        BitOp::Not | BitOp::Xor => &Instruction::I32Xor,
+        BitOp::And => &Instruction::I32And,
+        BitOp::Or => &Instruction::I32Or,
+    });
+    f.instruction(&Instruction::I32Store8(null_mem_arg()));
 }
 
 // TODO https://rsms.me/wasm-intro#addressing-memory
@@ -111,54 +818,137 @@ fn null_mem_arg() -> MemArg {
     }
 }
 
-fn add_debug_termination(f: &mut Function, js_debug_terminate: u32) {
-    f.instruction(&Instruction::LocalGet(DP));
-    f.instruction(&Instruction::LocalGet(DP));
+// Takes one unit of fuel, stopping the program if there is none left.
+// Helpers can't return from `main`, so they trap once the host was told.
+fn burn_fuel(f: &mut Frame, fuel: &Fuel, layout: &Layout, in_helper: bool) {
+    f.instruction(&Instruction::GlobalGet(FUEL));
+    f.instruction(&Instruction::I64Eqz);
+    f.instruction(&Instruction::If(BlockType::Empty));
+    match (fuel.out_of_fuel, layout.out_of_fuel) {
+        (OutOfFuel::Import, Some(out_of_fuel)) if in_helper => {
+            f.instruction(&Instruction::Call(out_of_fuel));
+            save_dp(f, layout);
+            f.instruction(&Instruction::Unreachable);
+        }
+        (OutOfFuel::Import, Some(out_of_fuel)) => {
+            f.instruction(&Instruction::Call(out_of_fuel));
+            save_dp(f, layout);
+            if layout.resume.is_some() {
+                f.instruction(&Instruction::I32Const(OUT_OF_FUEL));
+            }
+            f.instruction(&Instruction::Return);
+        }
+        _ => {
+            save_dp(f, layout);
+            f.instruction(&Instruction::Unreachable);
+        }
+    }
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::GlobalGet(FUEL));
+    f.instruction(&Instruction::I64Const(1));
+    f.instruction(&Instruction::I64Sub);
+    f.instruction(&Instruction::GlobalSet(FUEL));
+}
+
+fn save_dp(f: &mut Frame, layout: &Layout) {
+    if let Some(dp) = layout.dp {
+        f.save_dp(dp);
+    }
+}
+
+// Bumps counter `counter` of the profile table entry for `site`.
+fn count(f: &mut Frame, site: u32, counter: u32) {
+    let mem_arg = MemArg {
+        offset: (PROFILE_BASE + PROFILE_ENTRY * (site + 1) + 8 * counter) as u64,
+        align: 3,
+        memory_index: 0,
+    };
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I64Load(mem_arg));
+    f.instruction(&Instruction::I64Const(1));
+    f.instruction(&Instruction::I64Add);
+    f.instruction(&Instruction::I64Store(mem_arg));
+}
+
+fn add_debug_termination(f: &mut Frame, js_debug_terminate: u32) {
+    f.get_dp();
+    f.get_dp();
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
     f.instruction(&Instruction::Call(js_debug_terminate));
 }
 
-fn read(f: &mut Function, js_read: u32) {
-    f.instruction(&Instruction::LocalGet(DP));
+fn read(f: &mut Frame, js_read: u32) {
+    f.get_dp();
     f.instruction(&Instruction::Call(js_read));
     f.instruction(&Instruction::I32Store8(null_mem_arg()));
 }
 
-fn print(f: &mut Function, js_write: u32) {
-    f.instruction(&Instruction::LocalGet(DP));
-    f.instruction(&Instruction::I32Load8U(null_mem_arg()));
-    f.instruction(&Instruction::Call(js_write));
+// Reads the next byte of the input embedded at `base`, or zero once all
+// `len` bytes were read.
+fn read_embedded(f: &mut Frame, input_read: u32, base: u32, len: u32) {
+    f.get_dp();
+    f.instruction(&Instruction::GlobalGet(input_read));
+    f.instruction(&Instruction::I32Const(len as i32));
+    f.instruction(&Instruction::I32LtU);
+    f.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+    f.instruction(&Instruction::GlobalGet(input_read));
+    f.instruction(&Instruction::I32Load8U(MemArg {
+        offset: base as u64,
+        ..null_mem_arg()
+    }));
+    f.instruction(&Instruction::GlobalGet(input_read));
+    f.instruction(&Instruction::I32Const(1));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::GlobalSet(input_read));
+    f.instruction(&Instruction::Else);
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::I32Store8(null_mem_arg()));
 }
 
-fn add_or_sub(f: &mut Function, ct: usize, i: &Instruction) {
-    f.instruction(&Instruction::LocalGet(DP));
-    f.instruction(&Instruction::LocalGet(DP));
+fn print(f: &mut Frame, js_write: u32) {
+    f.get_dp();
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
-    f.instruction(&Instruction::I32Const(ct as i32));
-    f.instruction(i);
-    f.instruction(&Instruction::I32Store8(null_mem_arg()));
+    f.instruction(&Instruction::Call(js_write));
 }
 
-fn add(f: &mut Function, ct: usize) {
-    add_or_sub(f, ct, &Instruction::I32Add);
+// Pushes the address of the cell at `off` and returns the memarg to access
+// it with, using the memarg's unsigned offset for cells right of the pointer.
+fn cell_addr(f: &mut Frame, off: i32) -> MemArg {
+    f.get_dp();
+    if off < 0 {
+        f.instruction(&Instruction::I32Const(off));
+        f.instruction(&Instruction::I32Add);
+        return null_mem_arg();
+    }
+    MemArg {
+        offset: off as u64,
+        ..null_mem_arg()
+    }
 }
 
-fn sub(f: &mut Function, ct: usize) {
-    add_or_sub(f, ct, &Instruction::I32Sub);
+fn add(f: &mut Frame, d: i8, off: i32) {
+    let mem_arg = cell_addr(f, off);
+    cell_addr(f, off);
+    f.instruction(&Instruction::I32Load8U(mem_arg));
+    f.instruction(&Instruction::I32Const(d as i32));
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::I32Store8(mem_arg));
 }
 
-fn add_or_sub_from(f: &mut Function, ct: usize, off: i32, i: &Instruction) {
+fn add_or_sub_from(f: &mut Frame, ct: usize, off: i32, i: &Instruction) {
     // get offset number address
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Const(off));
     f.instruction(&Instruction::I32Add);
     // get offset number
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Const(off));
     f.instruction(&Instruction::I32Add);
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
     // get loop ct val
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
     // mul loop number by count
     if ct != 1 {
@@ -171,15 +961,15 @@ fn add_or_sub_from(f: &mut Function, ct: usize, off: i32, i: &Instruction) {
     f.instruction(&Instruction::I32Store8(null_mem_arg()));
 }
 
-fn add_from(f: &mut Function, ct: usize, off: i32) {
+fn add_from(f: &mut Frame, ct: usize, off: i32) {
     add_or_sub_from(f, ct, off, &Instruction::I32Add)
 }
 
-fn sub_from(f: &mut Function, ct: usize, off: i32) {
+fn sub_from(f: &mut Frame, ct: usize, off: i32) {
     add_or_sub_from(f, ct, off, &Instruction::I32Sub)
 }
 
-fn scan(f: &mut Function, stride: i32) {
+fn scan(f: &mut Frame, stride: i32) {
     if stride > 0 {
         for_scan(f, stride);
     } else {
@@ -187,24 +977,24 @@ fn scan(f: &mut Function, stride: i32) {
     }
 }
 
-fn rev_scan(f: &mut Function, stride: i32) {
+fn rev_scan(f: &mut Frame, stride: i32) {
     if stride != -1 && stride != -2 {
-        assert!(false);
+        unreachable!("unsupported reverse scan stride {}", stride);
     }
 
     simple_loop_start(f, 0);
 
     // Set the dp back by one vector
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Const(-16));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 
     f.instruction(&Instruction::Block(BlockType::Empty));
     f.instruction(&Instruction::Loop(BlockType::Empty));
 
     f.instruction(&Instruction::V128Const(0));
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::V128Load(null_mem_arg()));
     f.instruction(&Instruction::I8x16Eq);
 
@@ -219,34 +1009,34 @@ fn rev_scan(f: &mut Function, stride: i32) {
     f.instruction(&Instruction::I32Add);
 
     // if there is a value other than 16 then break
-    f.instruction(&Instruction::LocalTee(1));
+    f.tee_scratch();
     f.instruction(&Instruction::I32Const(16));
     f.instruction(&Instruction::I32Ne);
     f.instruction(&Instruction::BrIf(1));
 
     // Sub 16 to data pointer
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Const(-16));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 
     f.instruction(&Instruction::Br(0));
     f.instruction(&Instruction::End);
     f.instruction(&Instruction::End);
 
-    f.instruction(&Instruction::LocalGet(DP));
-    f.instruction(&Instruction::LocalGet(1));
+    f.get_dp();
+    f.get_scratch();
     f.instruction(&Instruction::I32Sub);
     f.instruction(&Instruction::I32Const(15));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 
     simple_loop_end(f);
 }
 
-fn for_scan(f: &mut Function, stride: i32) {
+fn for_scan(f: &mut Frame, stride: i32) {
     if stride != 1 && stride != 2 && stride != 4 {
-        assert!(false);
+        unreachable!("unsupported forward scan stride {}", stride);
     }
 
     simple_loop_start(f, 0);
@@ -256,7 +1046,7 @@ fn for_scan(f: &mut Function, stride: i32) {
     f.instruction(&Instruction::Loop(BlockType::Empty));
 
     f.instruction(&Instruction::V128Const(0));
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::V128Load(null_mem_arg()));
     f.instruction(&Instruction::I8x16Eq);
 
@@ -273,80 +1063,86 @@ fn for_scan(f: &mut Function, stride: i32) {
     f.instruction(&Instruction::I32Ctz);
 
     // if there is a value other than 32 then break
-    f.instruction(&Instruction::LocalTee(1));
+    f.tee_scratch();
     f.instruction(&Instruction::I32Const(32));
     f.instruction(&Instruction::I32Ne);
     f.instruction(&Instruction::BrIf(1));
 
     // Add 16 to data pointer
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_dp();
     f.instruction(&Instruction::I32Const(16));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 
     f.instruction(&Instruction::Br(0));
     f.instruction(&Instruction::End);
     f.instruction(&Instruction::End);
 
-    f.instruction(&Instruction::LocalGet(1));
-    f.instruction(&Instruction::LocalGet(DP));
+    f.get_scratch();
+    f.get_dp();
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 
     simple_loop_end(f);
 }
 
-fn set_0(f: &mut Function, off: i32) {
-    f.instruction(&Instruction::LocalGet(DP));
-    if off != 0 {
-        f.instruction(&Instruction::I32Const(off));
-        f.instruction(&Instruction::I32Add);
-    }
-    f.instruction(&Instruction::I32Const(0));
-    f.instruction(&Instruction::I32Store8(null_mem_arg()));
+fn set(f: &mut Frame, v: u8, off: i32) {
+    let mem_arg = cell_addr(f, off);
+    f.instruction(&Instruction::I32Const(v as i32));
+    f.instruction(&Instruction::I32Store8(mem_arg));
 }
 
-fn dp_r(f: &mut Function, ct: usize) {
-    f.instruction(&Instruction::LocalGet(DP));
+fn dp_r(f: &mut Frame, ct: usize) {
+    f.get_dp();
     f.instruction(&Instruction::I32Const(ct as i32));
     f.instruction(&Instruction::I32Add);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 }
 
-fn dp_l(f: &mut Function, ct: usize) {
-    f.instruction(&Instruction::LocalGet(DP));
+fn dp_l(f: &mut Frame, ct: usize) {
+    f.get_dp();
     f.instruction(&Instruction::I32Const(ct as i32));
     f.instruction(&Instruction::I32Sub);
-    f.instruction(&Instruction::LocalSet(DP));
+    f.set_dp();
 }
 
-fn loop_start(f: &mut Function) {
+fn loop_start(f: &mut Frame) {
     f.instruction(&Instruction::Block(BlockType::Empty));
     f.instruction(&Instruction::Loop(BlockType::Empty));
-    f.instruction(&Instruction::LocalGet(DP));
+    loop_test(f, 1);
+}
+
+// Leaves the loop if the current cell is zero, `depth` being the depth of
+// the block around it.
+fn loop_test(f: &mut Frame, depth: u32) {
+    f.get_dp();
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
     f.instruction(&Instruction::I32Eqz);
-    f.instruction(&Instruction::BrIf(1));
+    f.instruction(&Instruction::BrIf(depth));
 }
 
-fn loop_end(f: &mut Function) {
+fn loop_end(f: &mut Frame) {
     f.instruction(&Instruction::Br(0));
     f.instruction(&Instruction::End);
     f.instruction(&Instruction::End);
 }
 
-fn simple_loop_start(f: &mut Function, off: i32) {
+fn simple_loop_start(f: &mut Frame, off: i32) {
     f.instruction(&Instruction::Block(BlockType::Empty));
-    f.instruction(&Instruction::LocalGet(DP));
+    simple_loop_test(f, off, 0);
+}
+
+fn simple_loop_test(f: &mut Frame, off: i32, depth: u32) {
+    f.get_dp();
     if off != 0 {
         f.instruction(&Instruction::I32Const(off));
         f.instruction(&Instruction::I32Add);
     }
     f.instruction(&Instruction::I32Load8U(null_mem_arg()));
     f.instruction(&Instruction::I32Eqz);
-    f.instruction(&Instruction::BrIf(0));
+    f.instruction(&Instruction::BrIf(depth));
 }
 
-fn simple_loop_end(f: &mut Function) {
+fn simple_loop_end(f: &mut Frame) {
     f.instruction(&Instruction::End);
 }
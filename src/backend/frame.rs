@@ -0,0 +1,105 @@
+use std::ops::{Deref, DerefMut};
+
+use wasm_encoder::{Function, Instruction, ValType};
+
+// Where code finds the data pointer: in a local of every function, passed
+// from caller to helper and back, or in a global they all share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dp {
+    Local(u32),
+    Global(u32),
+}
+
+impl Dp {
+    // The first local free for the scratch register and the cache.
+    pub fn first_free_local(self) -> u32 {
+        match self {
+            Dp::Local(local) => local + 1,
+            Dp::Global(_) => 0,
+        }
+    }
+}
+
+// A function being built along with where its data pointer and scratch
+// register live. Other instructions go straight to the function.
+pub struct Frame {
+    f: Function,
+    dp: Dp,
+    scratch: u32,
+}
+
+impl Frame {
+    // `locals` must declare the scratch register, and the data pointer too
+    // if it is a local that isn't a parameter.
+    pub fn new(locals: Vec<(u32, ValType)>, dp: Dp) -> Frame {
+        Frame {
+            f: Function::new(locals),
+            dp,
+            scratch: dp.first_free_local(),
+        }
+    }
+
+    pub fn get_dp(&mut self) {
+        match self.dp {
+            Dp::Local(local) => self.f.instruction(&Instruction::LocalGet(local)),
+            Dp::Global(global) => self.f.instruction(&Instruction::GlobalGet(global)),
+        };
+    }
+
+    pub fn set_dp(&mut self) {
+        match self.dp {
+            Dp::Local(local) => self.f.instruction(&Instruction::LocalSet(local)),
+            Dp::Global(global) => self.f.instruction(&Instruction::GlobalSet(global)),
+        };
+    }
+
+    // Copies the data pointer to `global` for the host, unless it lives
+    // there already.
+    pub fn save_dp(&mut self, global: u32) {
+        if self.dp != Dp::Global(global) {
+            self.get_dp();
+            self.f.instruction(&Instruction::GlobalSet(global));
+        }
+    }
+
+    // Pushes the data pointer as the argument of a helper, when helpers
+    // take it as one.
+    pub fn pass_dp(&mut self) {
+        if let Dp::Local(_) = self.dp {
+            self.get_dp();
+        }
+    }
+
+    // Takes back the data pointer a helper returned.
+    pub fn take_dp(&mut self) {
+        if let Dp::Local(_) = self.dp {
+            self.set_dp();
+        }
+    }
+
+    pub fn get_scratch(&mut self) {
+        self.f.instruction(&Instruction::LocalGet(self.scratch));
+    }
+
+    pub fn tee_scratch(&mut self) {
+        self.f.instruction(&Instruction::LocalTee(self.scratch));
+    }
+
+    pub fn into_function(self) -> Function {
+        self.f
+    }
+}
+
+impl Deref for Frame {
+    type Target = Function;
+
+    fn deref(&self) -> &Function {
+        &self.f
+    }
+}
+
+impl DerefMut for Frame {
+    fn deref_mut(&mut self) -> &mut Function {
+        &mut self.f
+    }
+}
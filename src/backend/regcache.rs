@@ -0,0 +1,171 @@
+use wasm_encoder::Instruction;
+
+use super::frame::Frame;
+use super::{cell_addr, JS_READ, JS_WRITE};
+use crate::ir::Inst;
+
+pub const CACHE_SIZE: u32 = 16;
+
+struct Slot {
+    // Offset of the cell from the value held in `DP`.
+    off: i32,
+    local: u32,
+    dirty: bool,
+}
+
+// Keeps cells touched by straight-line code in wasm locals. Pointer
+// movement is deferred as well, so a run like `>+>+<<` never touches `DP`.
+// Cached values are only reduced mod 256 when they are stored or observed.
+pub struct RegCache {
+    first_local: u32,
+    dp_delta: i32,
+    slots: Vec<Slot>,
+}
+
+impl RegCache {
+    pub fn new(first_local: u32) -> RegCache {
+        RegCache {
+            first_local,
+            dp_delta: 0,
+            slots: vec![],
+        }
+    }
+
+    // Lowers `ins` if it can be done through the cache. Anything else is a
+    // block boundary and needs a `flush` before it is emitted.
+    pub fn lower(&mut self, f: &mut Frame, ins: &Inst) -> bool {
+        match *ins {
+            Inst::Right(ct) => self.dp_delta += ct as i32,
+            Inst::Left(ct) => self.dp_delta -= ct as i32,
+            Inst::Add(d, off) => {
+                let local = self.get(f, self.dp_delta + off);
+                f.instruction(&Instruction::LocalGet(local));
+                f.instruction(&Instruction::I32Const(d as i32));
+                f.instruction(&Instruction::I32Add);
+                f.instruction(&Instruction::LocalSet(local));
+                self.mark_dirty(local);
+            }
+            Inst::Set(v, off) => {
+                let local = self.slot(f, self.dp_delta + off);
+                f.instruction(&Instruction::I32Const(v as i32));
+                f.instruction(&Instruction::LocalSet(local));
+                self.mark_dirty(local);
+            }
+            Inst::AddFrom(ct, off) | Inst::SubFrom(ct, off) => {
+                let src = self.get(f, self.dp_delta);
+                // Fetching `dst` may evict the oldest cell, which mustn't be
+                // `src`.
+                self.renew(src);
+                let dst = self.get(f, self.dp_delta + off);
+                f.instruction(&Instruction::LocalGet(dst));
+                f.instruction(&Instruction::LocalGet(src));
+                if ct != 1 {
+                    f.instruction(&Instruction::I32Const(ct as i32));
+                    f.instruction(&Instruction::I32Mul);
+                }
+                match ins {
+                    Inst::AddFrom(..) => f.instruction(&Instruction::I32Add),
+                    _ => f.instruction(&Instruction::I32Sub),
+                };
+                f.instruction(&Instruction::LocalSet(dst));
+                self.mark_dirty(dst);
+            }
+            // Imports may look at memory, so it is brought up to date first.
+            Inst::Out => {
+                let local = self.get(f, self.dp_delta);
+                self.spill(f);
+                f.instruction(&Instruction::LocalGet(local));
+                f.instruction(&Instruction::I32Const(0xFF));
+                f.instruction(&Instruction::I32And);
+                f.instruction(&Instruction::Call(JS_WRITE));
+            }
+            Inst::In => {
+                self.spill(f);
+                let local = self.slot(f, self.dp_delta);
+                f.instruction(&Instruction::Call(JS_READ));
+                f.instruction(&Instruction::LocalSet(local));
+                self.mark_dirty(local);
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    // Writes back every cached cell and applies the deferred pointer
+    // movement, leaving memory and `DP` as uncached code expects them.
+    pub fn flush(&mut self, f: &mut Frame) {
+        self.spill(f);
+        if self.dp_delta != 0 {
+            f.get_dp();
+            f.instruction(&Instruction::I32Const(self.dp_delta));
+            f.instruction(&Instruction::I32Add);
+            f.set_dp();
+            self.dp_delta = 0;
+        }
+    }
+
+    fn spill(&mut self, f: &mut Frame) {
+        for slot in self.slots.drain(..) {
+            if slot.dirty {
+                store(f, &slot);
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, local: u32) {
+        for slot in &mut self.slots {
+            if slot.local == local {
+                slot.dirty = true;
+            }
+        }
+    }
+
+    // Makes the cell cached in `local` the last to be evicted.
+    fn renew(&mut self, local: u32) {
+        let idx = self.slots.iter().position(|slot| slot.local == local);
+        let slot = self.slots.remove(idx.unwrap());
+        self.slots.push(slot);
+    }
+
+    // Returns the local caching the cell at `off`, loading it if needed.
+    fn get(&mut self, f: &mut Frame, off: i32) -> u32 {
+        if let Some(slot) = self.slots.iter().find(|slot| slot.off == off) {
+            return slot.local;
+        }
+        let local = self.slot(f, off);
+        let mem_arg = cell_addr(f, off);
+        f.instruction(&Instruction::I32Load8U(mem_arg));
+        f.instruction(&Instruction::LocalSet(local));
+        local
+    }
+
+    // Returns a local for the cell at `off` without loading its value,
+    // evicting the oldest cached cell if every local is in use.
+    fn slot(&mut self, f: &mut Frame, off: i32) -> u32 {
+        if let Some(slot) = self.slots.iter().find(|slot| slot.off == off) {
+            return slot.local;
+        }
+        let local = if self.slots.len() < CACHE_SIZE as usize {
+            self.first_local + self.slots.len() as u32
+        } else {
+            let oldest = self.slots.remove(0);
+            if oldest.dirty {
+                store(f, &oldest);
+            }
+            oldest.local
+        };
+        self.slots.push(Slot {
+            off,
+            local,
+            dirty: false,
+        });
+        local
+    }
+}
+
+fn store(f: &mut Frame, slot: &Slot) {
+    let mem_arg = cell_addr(f, slot.off);
+    f.instruction(&Instruction::LocalGet(slot.local));
+    f.instruction(&Instruction::I32Store8(mem_arg));
+}
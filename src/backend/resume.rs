@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use wasm_encoder::{BlockType, Instruction};
+
+use super::frame::Frame;
+use super::{loop_test, null_mem_arg, simple_loop_test};
+use crate::ir::{Inst, IR};
+
+// Values returned by `main` and `resume` in resumable builds.
+pub const FINISHED: i32 = 0;
+pub const NEEDS_INPUT: i32 = 1;
+pub const OUT_OF_FUEL: i32 = 2;
+
+// Global indices of the suspended state, which follows the saved `DP`.
+#[derive(Debug, Clone, Copy)]
+pub struct ResumeGlobals {
+    pub saved_dp: u32,
+    pub resuming: u32,
+    // The `In` to continue at, numbered in program order.
+    pub point: u32,
+    // The byte passed to `resume`.
+    pub input: u32,
+}
+
+impl ResumeGlobals {
+    pub fn new(saved_dp: u32) -> ResumeGlobals {
+        ResumeGlobals {
+            saved_dp,
+            resuming: saved_dp + 1,
+            point: saved_dp + 2,
+            input: saved_dp + 3,
+        }
+    }
+}
+
+// Lowers `In` to a suspension of the program. Resuming jumps back into the
+// loop nest of the `In` it stopped at: every block on the way opens a wasm
+// block per child holding a resume point and a `br_table` picks the child
+// to start at, while loops on the way skip their test.
+pub struct Resumer {
+    globals: ResumeGlobals,
+    // Resume point of each `In`, by index in the IR.
+    points: HashMap<usize, u32>,
+    // For the top level (`None`) and each loop holding resume points, the
+    // children (by IR index) that hold resume points, with those points.
+    entries: HashMap<Option<usize>, Vec<(usize, Vec<u32>)>>,
+    // Children left to reach in each block that dispatched, innermost last.
+    // Each block's children are stored last first, so the next is popped.
+    pending: Vec<Vec<usize>>,
+}
+
+impl Resumer {
+    pub fn new(ir: &IR, globals: ResumeGlobals) -> Resumer {
+        let mut points = HashMap::new();
+        let mut entries: HashMap<Option<usize>, Vec<(usize, Vec<u32>)>> = HashMap::new();
+        // The open loops with the points found in them so far.
+        let mut open: Vec<(Option<usize>, Vec<u32>)> = vec![(None, vec![])];
+        // Procedures read input through `env.read`, as they can't suspend.
+        let mut in_proc = false;
+        for (idx, ins) in ir.iter().enumerate() {
+            match ins {
+                Inst::ProcStart | Inst::ProcEnd => in_proc = *ins == Inst::ProcStart,
+                _ if in_proc => (),
+                Inst::LoopStart | Inst::SimpleLoopStart(_) => open.push((Some(idx), vec![])),
+                Inst::LoopEnd | Inst::SimpleLoopEnd => {
+                    let (start, inner) = open.pop().unwrap();
+                    if !inner.is_empty() {
+                        let (parent, found) = open.last_mut().unwrap();
+                        found.extend(&inner);
+                        entries
+                            .entry(*parent)
+                            .or_default()
+                            .push((start.unwrap(), inner));
+                    }
+                }
+                Inst::In => {
+                    let point = points.len() as u32;
+                    points.insert(idx, point);
+                    let (block, found) = open.last_mut().unwrap();
+                    found.push(point);
+                    entries.entry(*block).or_default().push((idx, vec![point]));
+                }
+                _ => (),
+            }
+        }
+
+        Resumer {
+            globals,
+            points,
+            entries,
+            pending: vec![],
+        }
+    }
+
+    // True if a resume point lies in the loop starting at `idx`.
+    pub fn holds_point(&self, idx: usize) -> bool {
+        self.entries.contains_key(&Some(idx))
+    }
+
+    // True if `idx` starts a child a resumed run may jump to, in which case
+    // the caller must bring memory up to date and call `enter`.
+    pub fn is_entry(&self, idx: usize) -> bool {
+        self.pending
+            .last()
+            .is_some_and(|next| next.last() == Some(&idx))
+    }
+
+    pub fn enter(&mut self, f: &mut Frame) {
+        let next = self.pending.last_mut().unwrap();
+        next.pop();
+        if next.is_empty() {
+            self.pending.pop();
+        }
+        f.instruction(&Instruction::End);
+    }
+
+    // Sets up `DP` at the start of the program.
+    pub fn start(&mut self, f: &mut Frame) {
+        f.instruction(&Instruction::GlobalGet(self.globals.resuming));
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::GlobalGet(self.globals.saved_dp));
+        f.set_dp();
+        f.instruction(&Instruction::Else);
+        f.instruction(&Instruction::I32Const(16));
+        f.set_dp();
+        f.instruction(&Instruction::End);
+        self.dispatch(f, None);
+    }
+
+    // Loop headers for loops holding a resume point, which are entered
+    // without a test when resuming.
+    pub fn loop_start(&mut self, f: &mut Frame, idx: usize) {
+        f.instruction(&Instruction::Block(BlockType::Empty));
+        f.instruction(&Instruction::Loop(BlockType::Empty));
+        self.unless_resuming(f);
+        loop_test(f, 2);
+        f.instruction(&Instruction::End);
+        self.dispatch(f, Some(idx));
+    }
+
+    pub fn simple_loop_start(&mut self, f: &mut Frame, idx: usize, off: i32) {
+        f.instruction(&Instruction::Block(BlockType::Empty));
+        self.unless_resuming(f);
+        simple_loop_test(f, off, 1);
+        f.instruction(&Instruction::End);
+        self.dispatch(f, Some(idx));
+    }
+
+    // Reads the byte passed to `resume`, or suspends the program to wait
+    // for one.
+    pub fn read(&mut self, f: &mut Frame, idx: usize) {
+        let g = self.globals;
+        f.instruction(&Instruction::GlobalGet(g.resuming));
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::GlobalSet(g.resuming));
+        f.get_dp();
+        f.instruction(&Instruction::GlobalGet(g.input));
+        f.instruction(&Instruction::I32Store8(null_mem_arg()));
+        f.instruction(&Instruction::Else);
+        f.save_dp(g.saved_dp);
+        f.instruction(&Instruction::I32Const(self.points[&idx] as i32));
+        f.instruction(&Instruction::GlobalSet(g.point));
+        f.instruction(&Instruction::I32Const(NEEDS_INPUT));
+        f.instruction(&Instruction::Return);
+        f.instruction(&Instruction::End);
+    }
+
+    // Opens an `if` around code that must not run again when resuming.
+    pub fn unless_resuming(&self, f: &mut Frame) {
+        f.instruction(&Instruction::GlobalGet(self.globals.resuming));
+        f.instruction(&Instruction::I32Eqz);
+        f.instruction(&Instruction::If(BlockType::Empty));
+    }
+
+    // Opens a block per entry of `block` and, when resuming, branches to the
+    // end of the block right before the entry holding the resume point.
+    fn dispatch(&mut self, f: &mut Frame, block: Option<usize>) {
+        let Some(entries) = self.entries.get(&block) else {
+            return;
+        };
+        for _ in entries {
+            f.instruction(&Instruction::Block(BlockType::Empty));
+        }
+        // Points are numbered in program order, so those in the block are
+        // the ones from its first entry's first to its last entry's last,
+        // and the table only covers them. Inside the `if`, the block closed
+        // first is at depth 1.
+        let first = entries[0].1[0];
+        let mut targets = vec![];
+        for (depth, (_, points)) in entries.iter().enumerate() {
+            targets.extend(points.iter().map(|_| depth as u32 + 1));
+        }
+        f.instruction(&Instruction::GlobalGet(self.globals.resuming));
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::GlobalGet(self.globals.point));
+        f.instruction(&Instruction::I32Const(first as i32));
+        f.instruction(&Instruction::I32Sub);
+        f.instruction(&Instruction::BrTable(targets.into(), 0));
+        f.instruction(&Instruction::End);
+        self.pending
+            .push(entries.iter().rev().map(|(idx, _)| *idx).collect());
+    }
+}
@@ -0,0 +1,496 @@
+use std::fmt::Write;
+
+use crate::interp::{dump_tape, Machine, Trap, DP_START};
+use crate::ir::{Inst, IR};
+use crate::text::print_ir;
+use crate::Optimized;
+
+// The source commands an IR instruction was made from, as indices into the
+// commands of the program. Passes keep loop brackets and I/O in place, so
+// those come from a single command, while other instructions come from the
+// straight-line code between two of them, or from a single command of it
+// when it wasn't combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    pub first: usize,
+    pub last: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Insts(usize),
+    Commands(usize),
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(usize),
+    Watch { cell: usize, old: u8, new: u8 },
+    Finished,
+    Trapped(Trap),
+}
+
+pub enum Reply {
+    Text(String),
+    Quit,
+}
+
+struct Breakpoint {
+    label: String,
+    // Range of command indices that stops the program when entered.
+    first: usize,
+    last: usize,
+}
+
+impl Breakpoint {
+    fn covers(&self, origin: Origin) -> bool {
+        self.first <= origin.last && origin.first <= self.last
+    }
+}
+
+const HELP: &str = "\
+step [N]         run N bf commands (s)
+stepi [N]        run N IR instructions (si)
+continue         run to the next breakpoint or watchpoint (c)
+break LINE       stop when entering source line LINE (b)
+delete N         remove breakpoint N (d)
+watch CELL       stop when CELL changes
+unwatch CELL     remove the watchpoint on CELL
+info             list breakpoints and watchpoints
+tape [START [LEN]]  print cells, by default the rows around the pointer (t)
+where            show the current source position and IR instruction (w)
+list             show the IR around the current instruction (l)
+input TEXT       queue TEXT as program input, with \\n for a newline
+quit             leave the debugger (q)
+An empty line repeats the last step or continue. `#` in the source stops
+the program like a breakpoint.";
+
+// Runs a program on the IR interpreter with breakpoints on source lines and
+// `#` markers and watchpoints on cells. Cells are numbered from `DP_START`
+// as in `dump_tape`.
+pub struct Debugger<'a> {
+    machine: Machine<'a>,
+    ir: &'a IR,
+    source: &'a str,
+    // Line and column of every command of the source.
+    commands: Vec<(usize, usize)>,
+    origins: Vec<Origin>,
+    breakpoints: Vec<Option<Breakpoint>>,
+    watches: Vec<usize>,
+    printed: usize,
+    last: String,
+    // Set once the program ran, after which breakpoints only stop it when
+    // entered.
+    started: bool,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(source: &'a str, optimized: &'a Optimized) -> Debugger<'a> {
+        let mut commands = vec![];
+        let mut markers = vec![];
+        for (idx, line) in source.lines().enumerate() {
+            for (col, c) in line.chars().enumerate() {
+                match c {
+                    '+' | '-' | '<' | '>' | '[' | ']' | '.' | ',' => {
+                        commands.push((idx + 1, col + 1))
+                    }
+                    '#' => markers.push(commands.len()),
+                    _ => (),
+                }
+            }
+        }
+
+        let breakpoints = markers
+            .into_iter()
+            .filter(|command| *command < commands.len())
+            .map(|command| {
+                let (line, col) = commands[command];
+                Some(Breakpoint {
+                    label: format!("`#` before {}:{}", line, col),
+                    first: command,
+                    last: command,
+                })
+            })
+            .collect();
+
+        Debugger {
+            machine: Machine::new(&optimized.ir),
+            ir: &optimized.ir,
+            source,
+            origins: origins(source, optimized),
+            commands,
+            breakpoints,
+            watches: vec![],
+            printed: 0,
+            last: String::new(),
+            started: false,
+        }
+    }
+
+    // Output the program wrote since the last call.
+    pub fn take_output(&mut self) -> &[u8] {
+        let new = &self.machine.output[self.printed..];
+        self.printed = self.machine.output.len();
+        new
+    }
+
+    pub fn queue_input(&mut self, bytes: &[u8]) {
+        self.machine.input.extend(bytes);
+    }
+
+    pub fn execute(&mut self, line: &str) -> Reply {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => line.to_string(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let Some("step" | "s" | "stepi" | "si" | "continue" | "c") = words.first().copied() {
+            self.last = line.clone();
+        }
+        let count = |idx: usize| words.get(idx).and_then(|word| word.parse().ok());
+        let text = match words[..] {
+            [] => String::new(),
+            ["step" | "s", ..] => self.run(Mode::Commands(count(1).unwrap_or(1))),
+            ["stepi" | "si", ..] => self.run(Mode::Insts(count(1).unwrap_or(1))),
+            ["continue" | "c"] => self.run(Mode::Continue),
+            ["break" | "b", line] => match line.parse() {
+                Ok(line) => self.break_line(line),
+                Err(_) => format!("expected a line number, got `{}`", line),
+            },
+            ["delete" | "d", _] => match count(1) {
+                Some(idx) if self.breakpoints.get(idx).is_some_and(Option::is_some) => {
+                    self.breakpoints[idx] = None;
+                    format!("deleted breakpoint {}", idx)
+                }
+                _ => "no such breakpoint".to_string(),
+            },
+            ["watch", _] => match count(1) {
+                Some(cell) if DP_START + cell < self.machine.tape.len() => {
+                    self.watches.push(cell);
+                    format!("watching cell {}", cell)
+                }
+                _ => "expected a cell on the tape".to_string(),
+            },
+            ["unwatch", _] => match count(1) {
+                Some(cell) if self.watches.contains(&cell) => {
+                    self.watches.retain(|watched| *watched != cell);
+                    format!("stopped watching cell {}", cell)
+                }
+                _ => "no such watchpoint".to_string(),
+            },
+            ["info"] => self.info(),
+            ["tape" | "t", ..] => {
+                let dp = self.machine.dp as i64 - DP_START as i64;
+                let row = dp.max(0) as usize / 16 * 16;
+                let start = count(1).unwrap_or(row);
+                dump_tape(
+                    &self.machine.tape[DP_START..],
+                    dp,
+                    start,
+                    count(2).unwrap_or(32),
+                )
+            }
+            ["where" | "w"] => self.position(),
+            ["list" | "l"] => self.list(),
+            ["input", ..] => {
+                let bytes = unescape(line["input".len()..].trim_start());
+                self.queue_input(&bytes);
+                format!("queued {} bytes of input", bytes.len())
+            }
+            ["help" | "h"] => HELP.to_string(),
+            ["quit" | "q"] => return Reply::Quit,
+            _ => format!("unknown command `{}`, try `help`", line),
+        };
+        Reply::Text(text)
+    }
+
+    fn run(&mut self, mode: Mode) -> String {
+        let stop = self.resume(mode);
+        let mut out = String::new();
+        let _ = match stop {
+            Stop::Stepped => Ok(()),
+            Stop::Breakpoint(idx) => writeln!(
+                out,
+                "breakpoint {}, {}",
+                idx,
+                self.breakpoints[idx].as_ref().unwrap().label
+            ),
+            Stop::Watch { cell, old, new } => {
+                writeln!(out, "cell {} changed from {} to {}", cell, old, new)
+            }
+            Stop::Finished => {
+                return format!(
+                    "program finished on cell {}",
+                    self.machine.dp as i64 - DP_START as i64
+                )
+            }
+            Stop::Trapped(Trap::NeedsInput) => {
+                writeln!(out, "program needs input, queue some with `input TEXT`")
+            }
+            Stop::Trapped(Trap::PointerOutOfBounds(addr)) => writeln!(
+                out,
+                "data pointer left the tape (cell {})",
+                addr - DP_START as i64
+            ),
+            Stop::Trapped(Trap::UndefinedProcedure(cell)) => {
+                writeln!(out, "no procedure {} to call", cell)
+            }
+        };
+        out.push_str(&self.position());
+        out
+    }
+
+    fn resume(&mut self, mode: Mode) -> Stop {
+        let mut left = match mode {
+            Mode::Insts(n) | Mode::Commands(n) => n.max(1),
+            Mode::Continue => 0,
+        };
+        // A breakpoint on the first command stops `continue` before it runs.
+        let started = std::mem::replace(&mut self.started, true);
+        if !started && mode == Mode::Continue && !self.machine.finished() {
+            let at = self.origins[self.machine.pc];
+            let hit = self
+                .breakpoints
+                .iter()
+                .position(|bp| bp.as_ref().is_some_and(|bp| bp.covers(at)));
+            if let Some(idx) = hit {
+                return Stop::Breakpoint(idx);
+            }
+        }
+        loop {
+            if self.machine.finished() {
+                return Stop::Finished;
+            }
+            let from = self.origins[self.machine.pc];
+            let watched: Vec<u8> = self
+                .watches
+                .iter()
+                .map(|cell| self.machine.tape[DP_START + cell])
+                .collect();
+            if let Err(trap) = self.machine.step() {
+                return Stop::Trapped(trap);
+            }
+            for (cell, old) in self.watches.iter().zip(watched) {
+                let new = self.machine.tape[DP_START + cell];
+                if new != old {
+                    return Stop::Watch {
+                        cell: *cell,
+                        old,
+                        new,
+                    };
+                }
+            }
+            if self.machine.finished() {
+                return Stop::Finished;
+            }
+
+            let to = self.origins[self.machine.pc];
+            let moved = match mode {
+                Mode::Insts(_) => true,
+                Mode::Commands(_) => to != from,
+                Mode::Continue => false,
+            };
+            if moved {
+                left -= 1;
+                if left == 0 {
+                    return Stop::Stepped;
+                }
+            }
+            let hit = self.breakpoints.iter().position(|bp| {
+                bp.as_ref()
+                    .is_some_and(|bp| bp.covers(to) && !bp.covers(from))
+            });
+            if let Some(idx) = hit {
+                return Stop::Breakpoint(idx);
+            }
+        }
+    }
+
+    fn break_line(&mut self, line: usize) -> String {
+        let on_line: Vec<usize> = (0..self.commands.len())
+            .filter(|command| self.commands[*command].0 == line)
+            .collect();
+        match (on_line.first(), on_line.last()) {
+            (Some(first), Some(last)) => {
+                self.breakpoints.push(Some(Breakpoint {
+                    label: format!("line {}", line),
+                    first: *first,
+                    last: *last,
+                }));
+                format!("breakpoint {} at line {}", self.breakpoints.len() - 1, line)
+            }
+            _ => format!("no commands on line {}", line),
+        }
+    }
+
+    fn info(&self) -> String {
+        let mut out = String::new();
+        for (idx, bp) in self.breakpoints.iter().enumerate() {
+            if let Some(bp) = bp {
+                let _ = writeln!(out, "breakpoint {}: {}", idx, bp.label);
+            }
+        }
+        for cell in &self.watches {
+            let _ = writeln!(out, "watchpoint on cell {}", cell);
+        }
+        if out.is_empty() {
+            out.push_str("no breakpoints or watchpoints\n");
+        }
+        out
+    }
+
+    // The source of the next instruction, underlined, and the instruction.
+    fn position(&self) -> String {
+        let pc = self.machine.pc;
+        if pc >= self.ir.len() {
+            return "at the end of the program\n".to_string();
+        }
+        let origin = self.origins[pc];
+        let mut out = String::new();
+        if let (Some(&(line, col)), Some(&(last_line, last_col))) = (
+            self.commands.get(origin.first),
+            self.commands.get(origin.last),
+        ) {
+            let text = self.source.lines().nth(line - 1).unwrap_or("");
+            let end = match last_line == line {
+                true => last_col,
+                false => text.chars().count(),
+            };
+            let _ = writeln!(out, "{:>5}:{:<4} {}", line, col, text);
+            let _ = writeln!(
+                out,
+                "{:11}{}{}",
+                "",
+                " ".repeat(col - 1),
+                "^".repeat(end + 1 - col)
+            );
+        }
+        let ins = print_ir(&vec![self.ir[pc]]);
+        let _ = write!(out, "ir {}: {}", pc, ins.trim_start());
+        out
+    }
+
+    fn list(&self) -> String {
+        let pc = self.machine.pc;
+        let mut out = String::new();
+        for (idx, line) in print_ir(self.ir).lines().enumerate() {
+            if idx + 5 >= pc && idx <= pc + 5 {
+                let marker = if idx == pc { "=>" } else { "  " };
+                let _ = writeln!(out, "{} {:>5}  {}", marker, idx, line);
+            }
+        }
+        out
+    }
+}
+
+// Maps every instruction of `optimized` back to the commands of `source`
+// it was made from.
+pub fn origins(source: &str, optimized: &Optimized) -> Vec<Origin> {
+    let commands: Vec<char> = source.chars().filter(|c| "+-<>[].,".contains(*c)).collect();
+    let find = |kind: char| -> Vec<usize> {
+        (0..commands.len())
+            .filter(|idx| commands[*idx] == kind)
+            .collect()
+    };
+    let (opens, writes, reads) = (find('['), find('.'), find(','));
+    let mut closes = vec![0; opens.len()];
+    let mut open = vec![];
+    for (idx, c) in commands.iter().enumerate() {
+        match c {
+            '[' => open.push(opens.binary_search(&idx).unwrap()),
+            ']' => closes[open.pop().unwrap()] = idx,
+            _ => (),
+        }
+    }
+
+    // The command each loop bracket and I/O instruction came from.
+    let mut loops = optimized.loop_ids.iter();
+    let mut ends = vec![];
+    let (mut out, mut inp) = (writes.iter(), reads.iter());
+    let anchors: Vec<Option<usize>> = optimized
+        .ir
+        .iter()
+        .map(|ins| match ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) => {
+                let id = *loops.next().unwrap();
+                ends.push(id);
+                Some(opens[id])
+            }
+            Inst::LoopEnd | Inst::SimpleLoopEnd => Some(closes[ends.pop().unwrap()]),
+            Inst::Out => out.next().copied(),
+            Inst::In => inp.next().copied(),
+            _ => None,
+        })
+        .collect();
+
+    let mut origins = Vec::with_capacity(anchors.len());
+    let mut prev = None;
+    let mut idx = 0;
+    while idx < anchors.len() {
+        if let Some(command) = anchors[idx] {
+            prev = Some(command);
+            origins.push(Origin {
+                first: command,
+                last: command,
+            });
+            idx += 1;
+            continue;
+        }
+        // The run of instructions up to the next anchor and the commands
+        // between the anchors around it.
+        let end = anchors[idx..]
+            .iter()
+            .position(Option::is_some)
+            .map_or(anchors.len(), |len| idx + len);
+        let first = prev.map_or(0, |command| command + 1);
+        let last = anchors
+            .get(end)
+            .copied()
+            .flatten()
+            .unwrap_or(commands.len());
+        for n in 0..end - idx {
+            origins.push(match (first < last, prev) {
+                // A run `combine` left alone has an instruction per command.
+                (true, _) if last - first == end - idx => Origin {
+                    first: first + n,
+                    last: first + n,
+                },
+                (true, _) => Origin {
+                    first,
+                    last: last - 1,
+                },
+                // Code between two instructions made from the same
+                // command, like stores hoisted out of a loop.
+                (false, Some(command)) => Origin {
+                    first: command,
+                    last: command,
+                },
+                (false, None) => Origin { first: 0, last: 0 },
+            });
+        }
+        idx = end;
+    }
+
+    origins
+}
+
+// Turns `\n` into a newline and `\0` into a zero byte.
+pub fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('0') => '\0',
+                Some(c) => c,
+                None => '\\',
+            },
+            c => c,
+        };
+        bytes.extend(c.to_string().bytes());
+    }
+    bytes
+}
@@ -0,0 +1,169 @@
+use std::fmt::Write;
+
+use crate::ir::{check_simple, Inst};
+use crate::text::print_ir;
+use crate::tree::{Block, Node};
+
+// Renders the loop structure and basic blocks of `block` as a Graphviz
+// graph. Loops are drawn as clusters; loops left unconverted are red and
+// labelled with the reason `check_simple` gave.
+pub fn to_dot(block: &Block) -> String {
+    let mut graph = Graph {
+        out: String::new(),
+        edges: vec![],
+        next_id: 0,
+        exited: vec![],
+    };
+    graph.out.push_str("digraph ir {\n");
+    graph
+        .out
+        .push_str("    node [shape=box, fontname=monospace];\n");
+    let entry = graph.node("entry", "shape=oval");
+    let exits = graph.block(block, vec![(entry, "")], 1);
+    let exit = graph.node("exit", "shape=oval");
+    graph.connect(exits, exit);
+    let exited = std::mem::take(&mut graph.exited);
+    graph.connect(exited, exit);
+
+    for (from, to, label) in &graph.edges {
+        let _ = writeln!(graph.out, "    n{} -> n{} [label=\"{}\"];", from, to, label);
+    }
+    graph.out.push_str("}\n");
+    graph.out
+}
+
+// Nodes whose outgoing edge has yet to be connected, with the edge label.
+type Exits = Vec<(usize, &'static str)>;
+
+struct Graph {
+    out: String,
+    edges: Vec<(usize, usize, &'static str)>,
+    next_id: usize,
+    // `exit` instructions, which end the program wherever they are.
+    exited: Exits,
+}
+
+impl Graph {
+    fn indent(&mut self, depth: usize) {
+        for _ in 0..depth {
+            self.out.push_str("    ");
+        }
+    }
+
+    fn node(&mut self, label: &str, attrs: &str) -> usize {
+        self.node_at(label, attrs, 1)
+    }
+
+    fn node_at(&mut self, label: &str, attrs: &str, depth: usize) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.indent(depth);
+        let _ = match attrs {
+            "" => writeln!(self.out, "n{} [label=\"{}\"];", id, label),
+            _ => writeln!(self.out, "n{} [label=\"{}\", {}];", id, label, attrs),
+        };
+        id
+    }
+
+    fn connect(&mut self, exits: Exits, to: usize) {
+        for (from, label) in exits {
+            self.edges.push((from, to, label));
+        }
+    }
+
+    // Emits the nodes of `block`, connecting `preds` to its first node, and
+    // returns the nodes control leaves it from.
+    fn block(&mut self, block: &[Node], mut preds: Exits, depth: usize) -> Exits {
+        let mut run: Vec<Inst> = vec![];
+        for node in block {
+            if let Node::Inst(ins) = node {
+                if !matches!(ins, Inst::Scan(_) | Inst::Call | Inst::Exit) {
+                    run.push(*ins);
+                    continue;
+                }
+            }
+            preds = self.basic_block(&mut run, preds, depth);
+
+            match node {
+                Node::Inst(ins) => {
+                    let label = print_ir(&vec![*ins]).replace('\n', "\\l");
+                    let id = self.node_at(&label, "style=filled, fillcolor=lightblue", depth);
+                    self.connect(preds, id);
+                    preds = vec![(id, "")];
+                    if *ins == Inst::Exit {
+                        self.exited.append(&mut preds);
+                    }
+                }
+                Node::Loop(_, body) => {
+                    let (label, color) = match check_simple(body) {
+                        Ok(()) => ("loop (simple, not converted)".to_string(), "black"),
+                        Err(reason) => (format!("loop: {}", reason), "red"),
+                    };
+                    self.cluster_start(&label, color, depth);
+                    let test = self.node_at("test cell", "shape=diamond", depth + 1);
+                    self.connect(preds, test);
+                    let body_exits = self.block(body, vec![(test, "nonzero")], depth + 1);
+                    self.connect(body_exits, test);
+                    self.cluster_end(depth);
+                    preds = vec![(test, "zero")];
+                }
+                Node::SimpleLoop(_, off, body) => {
+                    self.cluster_start("simple loop (body runs at most once)", "darkgreen", depth);
+                    let label = format!("test cell {}", off);
+                    let test = self.node_at(&label, "shape=diamond", depth + 1);
+                    self.connect(preds, test);
+                    let mut body_exits = self.block(body, vec![(test, "nonzero")], depth + 1);
+                    self.cluster_end(depth);
+                    body_exits.push((test, "zero"));
+                    preds = body_exits;
+                }
+                // The body is only reached through `call`, so it hangs off
+                // its own entry.
+                Node::Proc(body) => {
+                    self.cluster_start("procedure (body runs when called)", "purple", depth);
+                    let define = self.node_at("define procedure cell", "", depth + 1);
+                    self.connect(preds, define);
+                    let called = self.node_at("called", "shape=oval", depth + 1);
+                    let body_exits = self.block(body, vec![(called, "")], depth + 1);
+                    let ret = self.node_at("return", "shape=oval", depth + 1);
+                    self.connect(body_exits, ret);
+                    self.cluster_end(depth);
+                    preds = vec![(define, "")];
+                }
+            }
+        }
+
+        self.basic_block(&mut run, preds, depth)
+    }
+
+    fn basic_block(&mut self, run: &mut Vec<Inst>, preds: Exits, depth: usize) -> Exits {
+        if run.is_empty() {
+            return preds;
+        }
+        let label = print_ir(run).replace('\n', "\\l");
+        let attrs = match run.iter().any(|ins| matches!(ins, Inst::Set(0, _))) {
+            true => "style=filled, fillcolor=lightyellow",
+            false => "",
+        };
+        run.clear();
+        let id = self.node_at(&label, attrs, depth);
+        self.connect(preds, id);
+        vec![(id, "")]
+    }
+
+    fn cluster_start(&mut self, label: &str, color: &str, depth: usize) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.indent(depth);
+        let _ = writeln!(self.out, "subgraph cluster_{} {{", id);
+        self.indent(depth + 1);
+        let _ = writeln!(self.out, "label=\"{}\";", label);
+        self.indent(depth + 1);
+        let _ = writeln!(self.out, "color={};", color);
+    }
+
+    fn cluster_end(&mut self, depth: usize) {
+        self.indent(depth);
+        self.out.push_str("}\n");
+    }
+}
@@ -0,0 +1,206 @@
+use crate::ir::{BitOp, Inst, IR};
+
+// The languages programs can be written in. Token substitutions of bf spell
+// its eight commands differently, while pbrain and Extended Brainfuck add
+// commands of their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Bf,
+    Ook,
+    Blub,
+    Alphuck,
+    // `(` and `)` define a procedure numbered by the current cell and `:`
+    // calls the one the current cell numbers.
+    Pbrain,
+    // Extended Brainfuck Type I.
+    Extended,
+}
+
+pub const DIALECT_NAMES: [&str; 6] = ["bf", "ook", "blub", "alphuck", "pbrain", "extended"];
+const DIALECTS: [Dialect; 6] = [
+    Dialect::Bf,
+    Dialect::Ook,
+    Dialect::Blub,
+    Dialect::Alphuck,
+    Dialect::Pbrain,
+    Dialect::Extended,
+];
+
+// The commands of bf, in the order `Dialect::commands` spells them.
+const COMMANDS: [Inst; 8] = [
+    Inst::Add(1, 0),
+    Inst::Add(-1, 0),
+    Inst::Right(1),
+    Inst::Left(1),
+    Inst::LoopStart,
+    Inst::LoopEnd,
+    Inst::Out,
+    Inst::In,
+];
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        let idx = DIALECT_NAMES.iter().position(|n| *n == name)?;
+        Some(DIALECTS[idx])
+    }
+
+    // How the dialect spells `+ - > < [ ] . ,`. A space in a token matches
+    // any run of whitespace.
+    fn commands(self) -> [&'static str; 8] {
+        match self {
+            Dialect::Bf | Dialect::Pbrain | Dialect::Extended => {
+                ["+", "-", ">", "<", "[", "]", ".", ","]
+            }
+            Dialect::Ook => [
+                "Ook. Ook.",
+                "Ook! Ook!",
+                "Ook. Ook?",
+                "Ook? Ook.",
+                "Ook! Ook?",
+                "Ook? Ook!",
+                "Ook! Ook.",
+                "Ook. Ook!",
+            ],
+            Dialect::Blub => [
+                "Blub. Blub.",
+                "Blub! Blub!",
+                "Blub. Blub?",
+                "Blub? Blub.",
+                "Blub! Blub?",
+                "Blub? Blub!",
+                "Blub! Blub.",
+                "Blub. Blub!",
+            ],
+            Dialect::Alphuck => ["e", "i", "a", "c", "p", "s", "j", "o"],
+        }
+    }
+
+    // Commands the dialect adds to bf.
+    fn extension(self, c: char) -> Option<Inst> {
+        match (self, c) {
+            (Dialect::Pbrain, '(') => Some(Inst::ProcStart),
+            (Dialect::Pbrain, ')') => Some(Inst::ProcEnd),
+            (Dialect::Pbrain, ':') => Some(Inst::Call),
+            (Dialect::Extended, '@') => Some(Inst::Exit),
+            (Dialect::Extended, '$') => Some(Inst::Save),
+            (Dialect::Extended, '!') => Some(Inst::Restore),
+            (Dialect::Extended, '{') => Some(Inst::Bitwise(BitOp::Shl)),
+            (Dialect::Extended, '}') => Some(Inst::Bitwise(BitOp::Shr)),
+            (Dialect::Extended, '~') => Some(Inst::Bitwise(BitOp::Not)),
+            (Dialect::Extended, '^') => Some(Inst::Bitwise(BitOp::Xor)),
+            (Dialect::Extended, '&') => Some(Inst::Bitwise(BitOp::And)),
+            (Dialect::Extended, '|') => Some(Inst::Bitwise(BitOp::Or)),
+            _ => None,
+        }
+    }
+}
+
+// Dialect extensions, all off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub dialect: Dialect,
+    // `#` dumps the pointer and the cells around it.
+    pub debug: bool,
+    // Text after the first `!` that isn't part of a command is the input of
+    // the program. Extended Brainfuck uses `!` as a command, so its programs
+    // never have input.
+    pub inline_input: bool,
+}
+
+// A command of the source and where it starts, with line and column both
+// counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub inst: Inst,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub fn parse(program: &str) -> IR {
+    parse_with(program, &ParseOptions::default())
+}
+
+// Splits off the input that follows `!` when `inline_input` is set. The `!`
+// in Ook! and Blub commands belongs to the program.
+pub fn split_input<'a>(program: &'a str, opts: &ParseOptions) -> (&'a str, Option<&'a str>) {
+    if !opts.inline_input || opts.dialect == Dialect::Extended {
+        return (program, None);
+    }
+    // Index of the end of the last command.
+    let mut end = 0;
+    for (idx, c) in program.char_indices() {
+        if idx < end {
+            continue;
+        }
+        match command(&program[idx..], opts) {
+            Some((len, _)) => end = idx + len,
+            None if c == '!' => return (&program[..idx], Some(&program[idx + 1..])),
+            None => (),
+        }
+    }
+    (program, None)
+}
+
+pub fn parse_with(program: &str, opts: &ParseOptions) -> IR {
+    tokens(split_input(program, opts).0, opts)
+        .iter()
+        .map(|token| token.inst)
+        .collect()
+}
+
+// The commands of `program`, which must not hold input, skipping everything
+// that isn't one.
+pub fn tokens(program: &str, opts: &ParseOptions) -> Vec<Token> {
+    let mut tokens = vec![];
+    let (mut line, mut column) = (1, 1);
+    // Index of the end of the last token.
+    let mut end = 0;
+    for (idx, c) in program.char_indices() {
+        if idx >= end {
+            if let Some((len, inst)) = command(&program[idx..], opts) {
+                tokens.push(Token { inst, line, column });
+                end = idx + len;
+            }
+        }
+        match c {
+            '\n' => (line, column) = (line + 1, 1),
+            _ => column += 1,
+        }
+    }
+
+    tokens
+}
+
+// The command `text` starts with, if any, and its length.
+fn command(text: &str, opts: &ParseOptions) -> Option<(usize, Inst)> {
+    let commands = opts.dialect.commands();
+    commands
+        .iter()
+        .zip(COMMANDS)
+        .find_map(|(spelling, inst)| Some((token_len(text, spelling)?, inst)))
+        .or_else(|| match text.chars().next()? {
+            '#' if opts.debug => Some((1, Inst::Debug)),
+            c => Some((c.len_utf8(), opts.dialect.extension(c)?)),
+        })
+}
+
+// Length of `token` at the start of `text`, if it is there.
+fn token_len(text: &str, token: &str) -> Option<usize> {
+    let mut len = 0;
+    for (idx, word) in token.split(' ').enumerate() {
+        if idx > 0 {
+            let rest = &text[len..];
+            let space = rest.len() - rest.trim_start().len();
+            if space == 0 {
+                return None;
+            }
+            len += space;
+        }
+        if !text[len..].starts_with(word) {
+            return None;
+        }
+        len += word.len();
+    }
+    Some(len)
+}
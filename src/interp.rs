@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use crate::ir::{Inst, IR};
+
+// Mirrors the memory layout of the generated module: a single 64KiB page
+// with the data pointer starting at address 16.
+pub const TAPE_LEN: usize = 65536;
+pub const DP_START: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    PointerOutOfBounds(i64),
+    NeedsInput,
+    // `Call` on a cell numbering no procedure.
+    UndefinedProcedure(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    StepLimit,
+    Trapped(Trap),
+}
+
+pub struct Machine<'a> {
+    ir: &'a [Inst],
+    jumps: Vec<usize>,
+    pub tape: Vec<u8>,
+    pub dp: usize,
+    pub pc: usize,
+    pub steps: usize,
+    pub output: Vec<u8>,
+    // Bytes left for `In`, which traps with `NeedsInput` once they run out
+    // unless `input_closed` is set, in which case it reads zero.
+    pub input: VecDeque<u8>,
+    pub input_closed: bool,
+    pub storage: u8,
+    // Index of the `ProcStart` of each defined procedure and the index to
+    // return to from each running one.
+    procs: [Option<usize>; 256],
+    calls: Vec<usize>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(ir: &'a IR) -> Machine<'a> {
+        Machine {
+            ir,
+            jumps: match_loops(ir),
+            tape: vec![0; TAPE_LEN],
+            dp: DP_START,
+            pc: 0,
+            steps: 0,
+            output: vec![],
+            input: VecDeque::new(),
+            input_closed: false,
+            storage: 0,
+            procs: [None; 256],
+            calls: vec![],
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pc >= self.ir.len()
+    }
+
+    fn addr(&self, off: i32) -> Result<usize, Trap> {
+        let addr = self.dp as i64 + off as i64;
+        if addr < 0 || addr >= TAPE_LEN as i64 {
+            return Err(Trap::PointerOutOfBounds(addr));
+        }
+        Ok(addr as usize)
+    }
+
+    fn cell(&self, off: i32) -> Result<u8, Trap> {
+        Ok(self.tape[self.addr(off)?])
+    }
+
+    fn move_dp(&mut self, delta: i64) -> Result<(), Trap> {
+        let addr = self.dp as i64 + delta;
+        if addr < 0 || addr >= TAPE_LEN as i64 {
+            return Err(Trap::PointerOutOfBounds(addr));
+        }
+        self.dp = addr as usize;
+        Ok(())
+    }
+
+    // Executes the instruction at `pc`. On a trap the machine is left
+    // pointing at the offending instruction.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        let ins = self.ir[self.pc];
+        let mut next = self.pc + 1;
+        match ins {
+            Inst::Add(d, off) => {
+                let addr = self.addr(off)?;
+                self.tape[addr] = self.tape[addr].wrapping_add(d as u8);
+            }
+            Inst::AddFrom(ct, off) => {
+                let val = self.cell(0)?.wrapping_mul(ct as u8);
+                let addr = self.addr(off)?;
+                self.tape[addr] = self.tape[addr].wrapping_add(val);
+            }
+            Inst::SubFrom(ct, off) => {
+                let val = self.cell(0)?.wrapping_mul(ct as u8);
+                let addr = self.addr(off)?;
+                self.tape[addr] = self.tape[addr].wrapping_sub(val);
+            }
+            Inst::Right(ct) => self.move_dp(ct as i64)?,
+            Inst::Left(ct) => self.move_dp(-(ct as i64))?,
+            Inst::In => {
+                let addr = self.addr(0)?;
+                self.tape[addr] = match self.input.pop_front() {
+                    Some(byte) => byte,
+                    None if self.input_closed => 0,
+                    None => return Err(Trap::NeedsInput),
+                };
+            }
+            Inst::Out => self.output.push(self.cell(0)?),
+            Inst::LoopStart => {
+                if self.cell(0)? == 0 {
+                    next = self.jumps[self.pc] + 1;
+                }
+            }
+            Inst::LoopEnd => next = self.jumps[self.pc],
+            Inst::SimpleLoopStart(off) => {
+                if self.cell(off)? == 0 {
+                    next = self.jumps[self.pc] + 1;
+                }
+            }
+            Inst::SimpleLoopEnd => (),
+            Inst::Set(v, off) => {
+                let addr = self.addr(off)?;
+                self.tape[addr] = v;
+            }
+            Inst::Debug => (),
+            Inst::ProcStart => {
+                self.procs[self.cell(0)? as usize] = Some(self.pc);
+                next = self.jumps[self.pc] + 1;
+            }
+            Inst::ProcEnd => next = self.calls.pop().expect("procedure end outside a call"),
+            Inst::Call => {
+                let cell = self.cell(0)?;
+                let start = self.procs[cell as usize].ok_or(Trap::UndefinedProcedure(cell))?;
+                self.calls.push(next);
+                next = start + 1;
+            }
+            Inst::Exit => next = self.ir.len(),
+            Inst::Save => self.storage = self.cell(0)?,
+            Inst::Restore => {
+                let addr = self.addr(0)?;
+                self.tape[addr] = self.storage;
+            }
+            Inst::Bitwise(op) => {
+                let addr = self.addr(0)?;
+                self.tape[addr] = op.apply(self.tape[addr], self.storage);
+            }
+            Inst::Scan(stride) => {
+                while self.cell(0)? != 0 {
+                    self.move_dp(stride as i64)?;
+                }
+            }
+        }
+        self.pc = next;
+        self.steps += 1;
+        Ok(())
+    }
+
+    pub fn run(&mut self, step_limit: usize) -> Outcome {
+        while !self.finished() {
+            if self.steps >= step_limit {
+                return Outcome::StepLimit;
+            }
+            if let Err(trap) = self.step() {
+                return Outcome::Trapped(trap);
+            }
+        }
+        Outcome::Finished
+    }
+}
+
+// Formats `len` cells of `tape` from cell `start`, 16 to a line, with the
+// cell at `dp` in brackets. Cells are numbered from `DP_START`, so `tape` is
+// the memory of a module or machine from that address on.
+pub fn dump_tape(tape: &[u8], dp: i64, start: usize, len: usize) -> String {
+    let end = (start + len).min(tape.len());
+    let mut out = String::new();
+    for row in (start..end).step_by(16) {
+        let _ = write!(out, "{:>6}:", row);
+        for (cell, val) in tape[row..(row + 16).min(end)].iter().enumerate() {
+            let _ = match (row + cell) as i64 == dp {
+                true => write!(out, "[{:>3}]", val),
+                false => write!(out, " {:>3} ", val),
+            };
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Maps every loop bracket to its partner, and procedure starts to their
+// ends. `LoopEnd` jumps back to its `LoopStart` so the condition is
+// re-tested.
+fn match_loops(ir: &IR) -> Vec<usize> {
+    let mut jumps = vec![0; ir.len()];
+    let mut stack = vec![];
+    for (idx, ins) in ir.iter().enumerate() {
+        match ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) | Inst::ProcStart => stack.push(idx),
+            Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd => {
+                let start = stack.pop().expect("unbalanced loop in IR");
+                jumps[start] = idx;
+                jumps[idx] = start;
+            }
+            _ => (),
+        }
+    }
+
+    jumps
+}
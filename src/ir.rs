@@ -1,7 +1,18 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+
+use crate::loops::{
+    analyze_block_with, analyze_body, analyze_body_with, analyze_loop, loop_info, BodyInfo,
+    Effects, Motion,
+};
+use crate::profile::LoopProfile;
+use crate::remarks::Remark;
+use crate::tree::{Block, Node};
+
 #[derive(PartialEq, Debug, Clone, Copy, Eq, PartialOrd, Ord)]
 pub enum Inst {
-    Add(Count), // TODO maybe add offset, or range
-    Sub(Count), // TODO maybe add offset, or range
+    Add(Delta, Offset),
     AddFrom(Count, Offset),
     SubFrom(Count, Offset),
     Left(Count),
@@ -12,251 +23,753 @@ pub enum Inst {
     LoopEnd,
     SimpleLoopStart(Offset),
     SimpleLoopEnd,
-    Zero(Offset),
+    Set(Value, Offset),
     Scan(i32),
+    // Hands the pointer to `env.debug`, which shows the cells around it.
+    Debug,
+    // pbrain procedures. `ProcStart` defines the procedure numbered by the
+    // current cell, whose body runs up to `ProcEnd` each time `Call` is
+    // executed on a cell holding that number.
+    ProcStart,
+    ProcEnd,
+    Call,
+    // Extended Brainfuck. `Exit` ends the program and `Save` and `Restore`
+    // copy the current cell to and from the storage cell.
+    Exit,
+    Save,
+    Restore,
+    Bitwise(BitOp),
 }
 
-type Offset = i32;
-type Count = usize;
-pub type IR = Vec<Inst>;
+// Updates of the current cell. The binary operations take the storage cell
+// as their other operand.
+#[derive(PartialEq, Debug, Clone, Copy, Eq, PartialOrd, Ord)]
+pub enum BitOp {
+    Shl,
+    Shr,
+    Not,
+    Xor,
+    And,
+    Or,
+}
 
-pub fn parse(program: &String) -> IR {
-    let mut ir: IR = vec![];
-
-    for ins in program.chars() {
-        match ins {
-            '+' => ir.push(Inst::Add(1)),
-            '-' => ir.push(Inst::Sub(1)),
-            '>' => ir.push(Inst::Right(1)),
-            '<' => ir.push(Inst::Left(1)),
-            '[' => ir.push(Inst::LoopStart),
-            ']' => ir.push(Inst::LoopEnd),
-            '.' => ir.push(Inst::Out),
-            ',' => ir.push(Inst::In),
-            _ => (),
+impl BitOp {
+    pub fn apply(self, cell: u8, storage: u8) -> u8 {
+        match self {
+            BitOp::Shl => cell << 1,
+            BitOp::Shr => cell >> 1,
+            BitOp::Not => !cell,
+            BitOp::Xor => cell ^ storage,
+            BitOp::And => cell & storage,
+            BitOp::Or => cell | storage,
         }
     }
+}
 
-    ir
+pub type Offset = i32;
+pub type Count = usize;
+pub type Delta = i8;
+pub type Value = u8;
+pub type IR = Vec<Inst>;
+
+// Builds a block, merging each cell update into an earlier update of the
+// same cell if only updates of other cells lie in between. The updates
+// since the last other node are held back, indexed by cell, until another
+// node is pushed, so every push takes constant time. An update merged away
+// leaves an empty slot to keep the rest in order.
+#[derive(Default)]
+struct Combiner {
+    block: Block,
+    run: Vec<Option<Inst>>,
+    cells: HashMap<Offset, usize>,
 }
 
-fn forward_scan(ir: &[Inst], ins: Inst) -> usize {
-    let mut ct = 0;
-    for i in ir {
-        if *i == ins {
-            ct += 1;
+impl Combiner {
+    fn push(&mut self, node: Node) {
+        let (ins, off) = match node {
+            Node::Inst(ins @ (Inst::Add(_, off) | Inst::Set(_, off))) => (ins, off),
+            node => {
+                self.flush();
+                self.block.push(node);
+                return;
+            }
+        };
+
+        let Some(&idx) = self.cells.get(&off) else {
+            if ins != Inst::Add(0, off) {
+                self.cells.insert(off, self.run.len());
+                self.run.push(Some(ins));
+            }
+            return;
+        };
+        let merged = match (self.run[idx], ins) {
+            (Some(Inst::Add(a, _)), Inst::Add(b, _)) => Inst::Add(a.wrapping_add(b), off),
+            (Some(Inst::Set(v, _)), Inst::Add(d, _)) => Inst::Set(v.wrapping_add(d as u8), off),
+            _ => ins,
+        };
+        if merged == Inst::Add(0, off) {
+            self.run[idx] = None;
+            self.cells.remove(&off);
         } else {
-            break;
+            self.run[idx] = Some(merged);
+        }
+    }
+
+    fn push_move(&mut self, dp: i32) {
+        match dp {
+            0 => (),
+            dp if dp > 0 => self.push(Node::Inst(Inst::Right(dp as usize))),
+            dp => self.push(Node::Inst(Inst::Left(-dp as usize))),
         }
     }
 
-    ct
+    fn flush(&mut self) {
+        self.block
+            .extend(self.run.drain(..).flatten().map(Node::Inst));
+        self.cells.clear();
+    }
+
+    fn finish(mut self) -> Block {
+        self.flush();
+        self.block
+    }
 }
 
-pub fn inst_combine(ir: &IR) -> IR {
-    let mut new_ir: IR = vec![];
-    let mut idx = 0;
-    while idx < ir.len() {
-        let ins = ir[idx];
-        match ins {
-            Inst::Add(_) => {
-                let ct = forward_scan(&ir[idx..], ins);
-                new_ir.push(Inst::Add(ct));
-                idx += ct;
-            }
-            Inst::Sub(_) => {
-                let ct = forward_scan(&ir[idx..], ins);
-                new_ir.push(Inst::Sub(ct));
-                idx += ct;
-            }
-            Inst::Right(_) => {
-                let ct = forward_scan(&ir[idx..], ins);
-                new_ir.push(Inst::Right(ct));
-                idx += ct;
-            }
-            Inst::Left(_) => {
-                let ct = forward_scan(&ir[idx..], ins);
-                new_ir.push(Inst::Left(ct));
-                idx += ct;
-            }
-            _ => {
-                new_ir.push(ins);
-                idx += 1;
+// Merges runs of cell updates and folds the pointer movement between them
+// into their offsets, so `>+>-<<` becomes `Add(1, 1), Add(-1, 2)`.
+pub fn inst_combine(block: &Block) -> Block {
+    combine_level(block.iter().map(|node| match node {
+        Node::Loop(id, body) => Node::Loop(*id, inst_combine(body)),
+        Node::SimpleLoop(id, off, body) => Node::SimpleLoop(*id, *off, inst_combine(body)),
+        Node::Proc(body) => Node::Proc(inst_combine(body)),
+        Node::Inst(_) => node.clone(),
+    }))
+}
+
+// Like `inst_combine`, but leaves the bodies of loops and procedures as they
+// are, for passes that rebuild one level of an already combined block.
+fn combine_level(nodes: impl IntoIterator<Item = Node>) -> Block {
+    let mut new_block = Combiner::default();
+    let mut dp: i32 = 0;
+    for node in nodes {
+        match node {
+            Node::Inst(Inst::Right(ct)) => dp += ct as i32,
+            Node::Inst(Inst::Left(ct)) => dp -= ct as i32,
+            Node::Inst(Inst::Add(d, off)) => new_block.push(Node::Inst(Inst::Add(d, off + dp))),
+            Node::Inst(Inst::Set(v, off)) => new_block.push(Node::Inst(Inst::Set(v, off + dp))),
+            node => {
+                new_block.push_move(dp);
+                dp = 0;
+                new_block.push(node);
             }
         }
     }
+    new_block.push_move(dp);
 
-    new_ir
+    new_block.finish()
 }
 
-fn single_loop_opt(ir: &IR) -> IR {
+fn single_loop_opt(body: Block) -> Block {
     let mut dp: i32 = 0;
-    let mut new_ir: IR = vec![Inst::SimpleLoopStart(0)];
-    for i in ir {
-        match i {
-            Inst::Right(ct) => dp += *ct as i32,
-            Inst::Left(ct) => dp -= *ct as i32,
-            Inst::Add(ct) => {
-                if dp != 0 {
-                    new_ir.push(Inst::AddFrom(*ct, dp));
-                }
-            }
-            Inst::Sub(ct) => {
-                if dp != 0 {
-                    new_ir.push(Inst::SubFrom(*ct, dp))
+    let mut new_body: Block = vec![];
+    // The loop runs `cell` times when the control cell counts down and
+    // `256 - cell` times when it counts up, which negates every factor.
+    let mut sign = 0;
+    for node in &body {
+        match *node {
+            Node::Inst(Inst::Right(ct)) => dp += ct as i32,
+            Node::Inst(Inst::Left(ct)) => dp -= ct as i32,
+            Node::Inst(Inst::Add(d, off)) if dp + off == 0 => sign = -(d as i32),
+            _ => (),
+        }
+    }
+
+    // Adds scale with the trip count and are hoisted to the front.
+    dp = 0;
+    for node in &body {
+        match *node {
+            Node::Inst(Inst::Right(ct)) => dp += ct as i32,
+            Node::Inst(Inst::Left(ct)) => dp -= ct as i32,
+            Node::Inst(Inst::Add(d, off)) if dp + off != 0 => match d as i32 * sign {
+                f if f > 0 => new_body.push(Node::Inst(Inst::AddFrom(f as usize, dp + off))),
+                f => new_body.push(Node::Inst(Inst::SubFrom(-f as usize, dp + off))),
+            },
+            _ => (),
+        }
+    }
+
+    // Everything else has the same effect on every trip after the first, so
+    // it only needs to run once. Inner loops keep their place among the
+    // other nodes, so the analyses of their bodies still line up.
+    let once = body
+        .into_iter()
+        .filter(|node| !matches!(node, Node::Inst(Inst::Add(..))));
+    new_body.extend(combine_level(once));
+
+    new_body.push(Node::Inst(Inst::Set(0, 0)));
+    new_body
+}
+
+// Turns loops that only move values between cells into a single
+// conditional block. Loops are visited bottom-up, so a loop whose inner
+// loops were all converted can be converted in turn.
+pub fn opt_simple_loops(block: &Block, remarks: &mut Vec<Remark>) -> Block {
+    simple_loops_block(block, remarks).0
+}
+
+// Returns the new block along with analyses of the bodies of the loops in
+// it, see `analyze_block_with`.
+fn simple_loops_block(block: &Block, remarks: &mut Vec<Remark>) -> (Block, Vec<BodyInfo>) {
+    let mut new_block: Block = Vec::with_capacity(block.len());
+    let mut infos = vec![];
+    for node in block {
+        match node {
+            Node::Loop(id, body) => {
+                let (body, inner) = simple_loops_block(body, remarks);
+                match check_simple_with(&body, &inner) {
+                    Ok(()) => {
+                        remarks.push(Remark::applied(
+                            "loops",
+                            *id,
+                            "converted to a simple loop".to_string(),
+                        ));
+                        let body = single_loop_opt(body);
+                        infos.push(analyze_body_with(&body, &inner));
+                        new_block.push(Node::SimpleLoop(*id, 0, body));
+                    }
+                    Err(reason) => {
+                        remarks.push(Remark::missed("loops", *id, reason.to_string()));
+                        infos.push(analyze_body_with(&body, &inner));
+                        new_block.push(Node::Loop(*id, body));
+                    }
                 }
             }
-            Inst::Zero(_) => new_ir.push(Inst::Zero(dp)),
-            Inst::SubFrom(ct, off) => new_ir.push(Inst::SubFrom(*ct, dp + off)),
-            _ => {
-                println!("{:?}", i);
-                assert!(false)
+            Node::SimpleLoop(id, off, body) => {
+                let (body, inner) = simple_loops_block(body, remarks);
+                infos.push(analyze_body_with(&body, &inner));
+                new_block.push(Node::SimpleLoop(*id, *off, body));
             }
+            Node::Proc(body) => new_block.push(Node::Proc(simple_loops_block(body, remarks).0)),
+            Node::Inst(_) => new_block.push(node.clone()),
         }
     }
 
-    new_ir.push(Inst::Zero(0));
-    new_ir.push(Inst::SimpleLoopEnd);
-    new_ir
+    (new_block, infos)
 }
 
-pub fn opt_simple_loops(ir: &IR) -> IR {
-    let mut new_ir = ir.clone();
-    let mut offset: i32 = 0;
-    let inner_loops = get_inner_loops(&new_ir);
-    for (start, end) in inner_loops {
-        let start_off: i32 = start as i32 + offset;
-        let end_off: i32 = end as i32 + offset;
-        let simple = is_simple(&new_ir, start_off as usize, end_off as usize);
-        let loop_ins = &new_ir[start_off as usize + 1..end_off as usize];
-        if simple {
-            let new_loop_ins = single_loop_opt(&loop_ins.to_vec());
-            offset += new_loop_ins.len() as i32 - (end_off - start_off) - 1;
-            new_ir = [
-                &new_ir[0..start_off as usize],
-                &new_loop_ins,
-                &new_ir[end_off as usize + 1..],
-            ]
-            .concat();
-        }
-    }
+// With a profile, scans that moved fewer than this many cells per entry on
+// average are left as loops, as the vector setup would cost more than it saves.
+pub const SCAN_MIN_DISTANCE: f64 = 16.0;
 
-    new_ir
+// Replaces loops that only move the pointer until they find a zero cell.
+pub fn scan_opt(block: &Block, profile: Option<&LoopProfile>, remarks: &mut Vec<Remark>) -> Block {
+    scan_block(block, profile, remarks).0
 }
 
-pub fn scan_opt(ir: &IR) -> IR {
-    let mut new_ir = ir.clone();
-    let mut offset = 0;
-    let mut stride: Option<i32> = None;
-
-    for (idx, window) in ir.to_vec().windows(3).enumerate() {
-        if let [i0, i1, i2] = window {
-            if *i0 == Inst::LoopStart && *i2 == Inst::LoopEnd {
-                match i1 {
-                    Inst::Left(s) => stride = Some(-(*s as i32)),
-                    Inst::Right(s) => stride = Some(*s as i32),
-                    _ => (),
-                }
-                if stride == Some(1)
-                    || stride == Some(2)
-                    || stride == Some(4)
-                    || stride == Some(-1)
-                    || stride == Some(-2)
-                {
-                    new_ir = [
-                        &new_ir[0..idx - offset],
-                        &[Inst::Scan(stride.unwrap())],
-                        &new_ir[idx - offset + 3..],
-                    ]
-                    .concat();
-                    offset += 2;
-
-                    stride = None;
+// Returns the new block along with analyses of the bodies of the loops left
+// in it, see `analyze_block_with`. Inner loops are done first so their
+// analyses can be reused, which only changes a loop's own analysis where an
+// inner loop became a scan, and then the loop drifts by an unknown amount
+// either way.
+fn scan_block(
+    block: &Block,
+    profile: Option<&LoopProfile>,
+    remarks: &mut Vec<Remark>,
+) -> (Block, Vec<BodyInfo>) {
+    let mut new_block: Block = Vec::with_capacity(block.len());
+    let mut infos = vec![];
+    for node in block {
+        match node {
+            Node::Loop(id, body) => {
+                // Remarks on inner loops still follow the remark on this one.
+                let mut inner_remarks = vec![];
+                let (body, inner) = scan_block(body, profile, &mut inner_remarks);
+                let body_info = analyze_body_with(&body, &inner);
+                let info = loop_info(&body_info);
+                let only_tests = info.effects
+                    == Effects {
+                        reads: BTreeSet::from([0]),
+                        ..Effects::default()
+                    };
+                let distance = |stride: i32| {
+                    let count = profile?.get(id)?;
+                    Some(count.trips_per_entry() * stride.abs() as f64)
+                        .filter(|_| count.reached > 0)
+                };
+                let missed = match info.motion {
+                    Motion::Drift(stride @ (1 | 2 | 4 | -1 | -2))
+                        if only_tests
+                            && distance(stride).is_some_and(|d| d < SCAN_MIN_DISTANCE) =>
+                    {
+                        Some(format!(
+                            "moved {:.1} cells per entry in the profile, too few for a vector scan",
+                            distance(stride).unwrap_or_default()
+                        ))
+                    }
+                    Motion::Drift(stride @ (1 | 2 | 4 | -1 | -2)) if only_tests => {
+                        remarks.push(Remark::applied(
+                            "scan",
+                            *id,
+                            format!("replaced with a scan by {}", stride),
+                        ));
+                        new_block.push(Node::Inst(Inst::Scan(stride)));
+                        continue;
+                    }
+                    Motion::Drift(stride) if only_tests => {
+                        Some(format!("no scan moves the pointer by {}", stride))
+                    }
+                    Motion::Drift(_) => Some("touches cells besides the one it tests".to_string()),
+                    _ => None,
+                };
+                if let Some(message) = missed {
+                    remarks.push(Remark::missed("scan", *id, message));
                 }
+                remarks.extend(inner_remarks);
+                infos.push(body_info);
+                new_block.push(Node::Loop(*id, body));
             }
+            Node::SimpleLoop(id, off, body) => {
+                let (body, inner) = scan_block(body, profile, remarks);
+                infos.push(analyze_body_with(&body, &inner));
+                new_block.push(Node::SimpleLoop(*id, *off, body));
+            }
+            Node::Proc(body) => new_block.push(Node::Proc(scan_block(body, profile, remarks).0)),
+            Node::Inst(_) => new_block.push(node.clone()),
         }
     }
 
-    new_ir
+    (new_block, infos)
 }
 
-pub fn cell_zero(ir: &IR) -> IR {
-    let mut new_ir = ir.clone();
-    let mut offset = 0;
-    for (idx, window) in ir.to_vec().windows(3).enumerate() {
-        if let [i0, i1, i2] = window {
-            if *i0 == Inst::LoopStart && *i2 == Inst::LoopEnd {
-                match i1 {
-                    Inst::Add(_) | Inst::Sub(_) => {
-                        new_ir = [
-                            &new_ir[0..idx - offset],
-                            &[Inst::Zero(0)],
-                            &new_ir[idx - offset + 3..],
-                        ]
-                        .concat();
-                        offset += 2;
+// Loops that ran at least this many trips, at least `UNROLL_MIN_AVG` per
+// entry, are unrolled if their body has at most `UNROLL_MAX_NODES` nodes.
+pub const UNROLL_MIN_TRIPS: u64 = 10_000;
+pub const UNROLL_MIN_AVG: f64 = 4.0;
+pub const UNROLL_MAX_NODES: usize = 32;
+
+// Unrolls hot balanced loops once, following the body with a copy that only
+// runs if the loop would go round again. This halves the backward branches
+// taken by the loop.
+pub fn unroll_hot_loops(block: &Block, profile: &LoopProfile, remarks: &mut Vec<Remark>) -> Block {
+    block
+        .iter()
+        .map(|node| match node {
+            Node::Loop(id, body) => {
+                let body = unroll_hot_loops(body, profile, remarks);
+                let count = match profile.get(id) {
+                    Some(count)
+                        if count.body >= UNROLL_MIN_TRIPS
+                            && count.trips_per_entry() >= UNROLL_MIN_AVG =>
+                    {
+                        count
                     }
-                    _ => (),
+                    _ => return Node::Loop(*id, body),
+                };
+                let missed = if !analyze_loop(&body).is_exact() {
+                    Some("the loop doesn't access the same cells on every trip".to_string())
+                } else if body.iter().any(|node| matches!(node, Node::Loop(..))) {
+                    Some("the loop contains another loop".to_string())
+                } else if body.len() > UNROLL_MAX_NODES {
+                    Some(format!("the body has more than {} nodes", UNROLL_MAX_NODES))
+                } else {
+                    None
+                };
+                if let Some(message) = missed {
+                    remarks.push(Remark::missed("unroll", *id, message));
+                    return Node::Loop(*id, body);
                 }
+
+                remarks.push(Remark::applied(
+                    "unroll",
+                    *id,
+                    format!(
+                        "unrolled once, ran {} trips at {:.1} per entry",
+                        count.body,
+                        count.trips_per_entry()
+                    ),
+                ));
+                let mut unrolled = body.clone();
+                unrolled.push(Node::SimpleLoop(*id, 0, body));
+                Node::Loop(*id, unrolled)
+            }
+            Node::SimpleLoop(id, off, body) => {
+                Node::SimpleLoop(*id, *off, unroll_hot_loops(body, profile, remarks))
             }
+            Node::Proc(body) => Node::Proc(unroll_hot_loops(body, profile, remarks)),
+            Node::Inst(_) => node.clone(),
+        })
+        .collect()
+}
+
+// Moves stores out of loops when nothing else in the loop touches the
+// stored cell. The store then happens once, after the loop, provided the
+// loop ran at all.
+pub fn hoist_invariants(block: &Block, remarks: &mut Vec<Remark>) -> Block {
+    hoist_block(block, remarks).0
+}
+
+// Returns the new block along with analyses of the bodies of the loops in
+// it, see `analyze_block_with`.
+fn hoist_block(block: &Block, remarks: &mut Vec<Remark>) -> (Block, Vec<BodyInfo>) {
+    let mut new_block: Block = Vec::with_capacity(block.len());
+    let mut infos = vec![];
+    for node in block {
+        match node {
+            Node::Loop(id, body) => {
+                let (mut body, inner) = hoist_block(body, remarks);
+                let stores = body
+                    .iter()
+                    .filter(|node| matches!(node, Node::Inst(Inst::Set(..))))
+                    .count();
+                let body_info = analyze_body_with(&body, &inner);
+                let info = loop_info(&body_info);
+                if !info.is_exact() {
+                    if stores > 0 {
+                        remarks.push(Remark::missed(
+                            "licm",
+                            *id,
+                            "the loop doesn't access the same cells on every trip".to_string(),
+                        ));
+                    }
+                    infos.push(body_info);
+                    new_block.push(Node::Loop(*id, body));
+                    continue;
+                }
+
+                let mut hoisted: Block = vec![];
+                let mut dp = 0;
+                body.retain(|node| match *node {
+                    Node::Inst(Inst::Right(ct)) => {
+                        dp += ct as i32;
+                        true
+                    }
+                    Node::Inst(Inst::Left(ct)) => {
+                        dp -= ct as i32;
+                        true
+                    }
+                    Node::Inst(Inst::Set(v, off))
+                        if !info.effects.reads(dp + off) && info.effects.writers(dp + off) == 1 =>
+                    {
+                        hoisted.push(Node::Inst(Inst::Set(v, dp + off)));
+                        false
+                    }
+                    _ => true,
+                });
+
+                if hoisted.len() < stores {
+                    remarks.push(Remark::missed(
+                        "licm",
+                        *id,
+                        format!(
+                            "{} of {} stores are to cells the loop also reads or writes elsewhere",
+                            stores - hoisted.len(),
+                            stores
+                        ),
+                    ));
+                }
+                if hoisted.is_empty() {
+                    infos.push(body_info);
+                    new_block.push(Node::Loop(*id, body));
+                    continue;
+                }
+                remarks.push(Remark::applied(
+                    "licm",
+                    *id,
+                    format!("moved {} stores out of the loop", hoisted.len()),
+                ));
+                // Only the stores are gone, so the inner loops are the same.
+                let body = combine_level(body);
+                let loop_body = analyze_body_with(&body, &inner);
+                let mut guarded = vec![Node::Loop(*id, body)];
+                guarded.extend(hoisted);
+                infos.push(analyze_body_with(&guarded, &[loop_body]));
+                new_block.push(Node::SimpleLoop(*id, 0, guarded));
+            }
+            Node::SimpleLoop(id, off, body) => {
+                let (body, inner) = hoist_block(body, remarks);
+                infos.push(analyze_body_with(&body, &inner));
+                new_block.push(Node::SimpleLoop(*id, *off, body));
+            }
+            Node::Proc(body) => new_block.push(Node::Proc(hoist_block(body, remarks).0)),
+            Node::Inst(_) => new_block.push(node.clone()),
         }
     }
 
-    new_ir
+    (new_block, infos)
 }
 
-pub fn get_inner_loops(ir: &IR) -> Vec<(usize, usize)> {
-    let mut inner_loops: Vec<(usize, usize)> = Vec::new();
-    let mut top_paren: Option<usize> = None;
-    for (idx, ins) in ir.iter().enumerate() {
-        match ins {
-            Inst::LoopStart => top_paren = Some(idx),
-            Inst::LoopEnd => match top_paren {
-                None => (),
-                Some(x) => {
-                    inner_loops.push((x, idx));
-                    top_paren = None;
+pub fn cell_zero(block: &Block, remarks: &mut Vec<Remark>) -> Block {
+    let mut new_block = Combiner::default();
+    for node in block {
+        match node {
+            Node::Loop(id, body) => match body[..] {
+                [Node::Inst(Inst::Add(_, 0))] => {
+                    remarks.push(Remark::applied(
+                        "zero",
+                        *id,
+                        "replaced with `set 0`".to_string(),
+                    ));
+                    new_block.push(Node::Inst(Inst::Set(0, 0)))
                 }
+                _ => new_block.push(Node::Loop(*id, cell_zero(body, remarks))),
             },
-            _ => (),
+            Node::SimpleLoop(id, off, body) => {
+                new_block.push(Node::SimpleLoop(*id, *off, cell_zero(body, remarks)))
+            }
+            Node::Proc(body) => new_block.push(Node::Proc(cell_zero(body, remarks))),
+            Node::Inst(_) => new_block.push(node.clone()),
         }
     }
 
-    inner_loops
+    new_block.finish()
 }
 
-pub fn is_simple(ir: &IR, start: usize, end: usize) -> bool {
-    let loop_ins = &ir[start + 1..end];
-    let mut ret = true;
+// Why `check_simple` rejected a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotSimple {
+    Io,
+    InnerLoop,
+    PointerDrift(i32),
+    NonUnitControl(Delta),
+    NoControlUpdate,
+    MultipleControlWrites,
+    ControlSet,
+    // Offsets are relative to the cell the loop tests.
+    NonLinear(Offset),
+    InnerLoopInput(Offset),
+    Procedure,
+    Extension,
+}
 
-    if loop_ins.contains(&Inst::In) || loop_ins.contains(&Inst::Out) {
-        ret = false;
+impl fmt::Display for NotSimple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotSimple::Io => write!(f, "performs I/O"),
+            NotSimple::InnerLoop => write!(f, "contains a loop that couldn't be simplified"),
+            NotSimple::PointerDrift(d) => write!(f, "moves the pointer by {} per trip", d),
+            NotSimple::NonUnitControl(d) => write!(f, "changes the control cell by {}", d),
+            NotSimple::NoControlUpdate => write!(f, "never changes the control cell"),
+            NotSimple::MultipleControlWrites => write!(f, "writes the control cell more than once"),
+            NotSimple::ControlSet => write!(f, "overwrites the control cell"),
+            NotSimple::NonLinear(off) => {
+                write!(f, "cell {} is both overwritten and updated", off)
+            }
+            NotSimple::InnerLoopInput(off) => {
+                write!(
+                    f,
+                    "an inner loop reads cell {}, which changes every trip",
+                    off
+                )
+            }
+            NotSimple::Procedure => write!(f, "defines or calls a procedure"),
+            NotSimple::Extension => write!(f, "uses the storage cell or ends the program"),
+        }
     }
+}
 
+pub fn is_simple(body: &[Node]) -> bool {
+    check_simple(body).is_ok()
+}
+
+pub fn check_simple(body: &[Node]) -> Result<(), NotSimple> {
+    let inner: Vec<BodyInfo> = body
+        .iter()
+        .filter_map(|node| match node {
+            Node::Loop(_, body) | Node::SimpleLoop(_, _, body) => Some(analyze_body(body)),
+            _ => None,
+        })
+        .collect();
+    check_simple_with(body, &inner)
+}
+
+// Like `check_simple`, but takes the analyses of the bodies of the inner
+// loops from `inner`, see `analyze_block_with`.
+pub fn check_simple_with(body: &[Node], inner_bodies: &[BodyInfo]) -> Result<(), NotSimple> {
+    let mut inner_bodies = inner_bodies.iter();
     let mut ptr_change: i32 = 0;
-    let mut loop_ptr_changed = false;
-    for ins in loop_ins {
-        match ins {
-            Inst::Right(ct) => ptr_change += *ct as i32,
-            Inst::Left(ct) => ptr_change -= *ct as i32,
-            Inst::Add(ct) | Inst::Sub(ct) => {
-                if ptr_change == 0 {
-                    if *ct != 1 {
-                        ret = false;
-                    }
-                    match loop_ptr_changed {
-                        true => ret = false,
-                        false => loop_ptr_changed = true,
-                    }
+    let mut control_writes = 0;
+    let mut added: BTreeSet<Offset> = BTreeSet::new();
+    let mut set: Vec<Offset> = vec![];
+    // Effects of each already converted inner loop, and how many of them
+    // write each cell.
+    let mut inner: Vec<Effects> = vec![];
+    let mut inner_writers: HashMap<Offset, usize> = HashMap::new();
+    for node in body {
+        // Taken up front so the analyses stay in step with the loops.
+        let inner_body = match node {
+            Node::Loop(..) | Node::SimpleLoop(..) => inner_bodies.next(),
+            _ => None,
+        };
+        match *node {
+            Node::Inst(Inst::Right(ct)) => ptr_change += ct as i32,
+            Node::Inst(Inst::Left(ct)) => ptr_change -= ct as i32,
+            Node::Inst(Inst::Add(d, off)) if ptr_change + off == 0 => {
+                if d != 1 && d != -1 {
+                    return Err(NotSimple::NonUnitControl(d));
                 }
+                control_writes += 1;
             }
-            _ => (),
+            Node::Inst(Inst::Add(_, off)) => {
+                added.insert(ptr_change + off);
+            }
+            Node::Inst(Inst::Set(_, off)) => set.push(ptr_change + off),
+            Node::Inst(Inst::In | Inst::Out | Inst::Debug) => return Err(NotSimple::Io),
+            Node::Proc(_) | Node::Inst(Inst::Call) => return Err(NotSimple::Procedure),
+            Node::Inst(Inst::Exit | Inst::Save | Inst::Restore | Inst::Bitwise(_)) => {
+                return Err(NotSimple::Extension)
+            }
+            // Converted loops zero the cell they test as their last step.
+            Node::SimpleLoop(_, 0, ref body)
+                if body.last() == Some(&Node::Inst(Inst::Set(0, 0))) =>
+            {
+                let mut effects = Effects::default();
+                let end = analyze_block_with(
+                    std::slice::from_ref(node),
+                    ptr_change,
+                    &mut effects,
+                    std::slice::from_ref(inner_body.expect("no analysis for an inner loop")),
+                );
+                if effects.io {
+                    return Err(NotSimple::Io);
+                }
+                if end != Some(ptr_change) {
+                    return Err(NotSimple::InnerLoop);
+                }
+                for off in effects.writes.keys() {
+                    *inner_writers.entry(*off).or_insert(0) += 1;
+                }
+                inner.push(effects);
+            }
+            _ => return Err(NotSimple::InnerLoop),
         }
     }
 
     if ptr_change != 0 {
-        ret = false;
+        return Err(NotSimple::PointerDrift(ptr_change));
+    }
+    if set.contains(&0) {
+        return Err(NotSimple::ControlSet);
+    }
+    match control_writes {
+        0 => return Err(NotSimple::NoControlUpdate),
+        1 => (),
+        _ => return Err(NotSimple::MultipleControlWrites),
     }
 
-    ret
+    // A cell that is both set and added to isn't linear in the trip count,
+    // and neither is one an inner loop overwrites. The adds are all moved
+    // ahead of the inner loops, whose stores would then replace them.
+    if let Some(off) = set
+        .iter()
+        .find(|off| added.contains(off) || inner_writers.contains_key(off))
+        .or_else(|| added.iter().find(|off| inner_writers.contains_key(off)))
+    {
+        return Err(NotSimple::NonLinear(*off));
+    }
+
+    // Inner loops zero their control cell, so they do nothing after the first
+    // trip as long as nothing else writes the cells they read.
+    let set: BTreeSet<Offset> = set.into_iter().collect();
+    for effects in &inner {
+        if effects.writes(0) {
+            return Err(NotSimple::MultipleControlWrites);
+        }
+        if let Some(off) = effects.reads.iter().find(|off| {
+            **off == 0
+                || added.contains(off)
+                || set.contains(off)
+                || inner_writers
+                    .get(off)
+                    .is_some_and(|n| *n > usize::from(effects.writes.contains_key(off)))
+        }) {
+            return Err(NotSimple::InnerLoopInput(*off));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub index: usize,
+    pub inst: Inst,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "instruction {} ({:?}): {}",
+            self.index, self.inst, self.message
+        )
+    }
+}
+
+impl Error for VerifyError {}
+
+// Checks that loop and procedure markers pair up and that every operand is
+// one the backend can lower.
+pub fn verify(ir: &IR) -> Result<(), VerifyError> {
+    let mut open: Vec<(usize, Inst)> = vec![];
+    for (index, ins) in ir.iter().enumerate() {
+        let err = |message: &str| VerifyError {
+            index,
+            inst: *ins,
+            message: message.to_string(),
+        };
+        match *ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) => open.push((index, *ins)),
+            Inst::ProcStart if in_proc(&open) => {
+                return Err(err("procedures can't be defined inside procedures"))
+            }
+            Inst::ProcStart => open.push((index, *ins)),
+            Inst::LoopEnd => match open.pop() {
+                Some((_, Inst::LoopStart)) => (),
+                Some((_, Inst::ProcStart)) => return Err(err("closes a procedure")),
+                Some(_) => return Err(err("closes a simple loop")),
+                None => return Err(err("no loop to close")),
+            },
+            Inst::SimpleLoopEnd => match open.pop() {
+                Some((_, Inst::SimpleLoopStart(_))) => (),
+                Some((_, Inst::ProcStart)) => return Err(err("closes a procedure")),
+                Some(_) => return Err(err("closes a non-simple loop")),
+                None => return Err(err("no simple loop to close")),
+            },
+            Inst::ProcEnd => match open.pop() {
+                Some((_, Inst::ProcStart)) => (),
+                Some(_) => return Err(err("closes a loop")),
+                None => return Err(err("no procedure to close")),
+            },
+            Inst::Exit if in_proc(&open) => {
+                return Err(err("exit can only end the program outside of procedures"))
+            }
+            Inst::Scan(stride) if !matches!(stride, 1 | 2 | 4 | -1 | -2) => {
+                return Err(err("scan stride must be one of 1, 2, 4, -1 or -2"))
+            }
+            Inst::Left(ct) | Inst::Right(ct) | Inst::AddFrom(ct, _) | Inst::SubFrom(ct, _)
+                if ct > i32::MAX as usize =>
+            {
+                return Err(err("count does not fit in an i32"))
+            }
+            _ => (),
+        }
+    }
+
+    match open.pop() {
+        Some((index, inst)) => Err(VerifyError {
+            index,
+            inst,
+            message: match inst {
+                Inst::ProcStart => "procedure is never closed",
+                _ => "loop is never closed",
+            }
+            .to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn in_proc(open: &[(usize, Inst)]) -> bool {
+    open.iter().any(|(_, ins)| *ins == Inst::ProcStart)
 }
@@ -1,14 +1,290 @@
-mod ir;
-use backend::create_wasm;
-use ir::{cell_zero, inst_combine, opt_simple_loops, parse, scan_opt};
-mod backend;
+pub mod backend;
+pub mod debugger;
+pub mod dot;
+pub mod frontend;
+pub mod interp;
+pub mod ir;
+pub mod loops;
+pub mod profile;
+pub mod remarks;
+#[cfg(feature = "runtime")]
+pub mod runtime;
+pub mod text;
+pub mod tree;
 
-// TODO make a function for displaying the IR
-// TODO make the optimizations optional
+use backend::{create_output_wasm, create_wasm, BackendOptions, Fuel, FuelBudget, OutOfFuel};
+use frontend::{parse_with, split_input, tokens, Dialect, ParseOptions};
+use interp::{Machine, Outcome};
+use ir::{
+    cell_zero, hoist_invariants, inst_combine, opt_simple_loops, scan_opt, unroll_hot_loops,
+    verify, Inst, VerifyError, IR,
+};
+use profile::{sites, sites_section, LoopProfile};
+use remarks::{loop_spans, Remark};
+use std::error::Error;
+use std::fmt;
+use text::{print_ir, stages_to_json};
+use tree::{check_depth, from_flat, loop_ids, to_flat, Block, LoopId, StructureError};
 
 // #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    pub parse: ParseOptions,
+    pub cell_zero_opt: bool,
+    pub loop_opt: bool,
+    pub licm: bool,
+    pub scan_opt: bool,
+    // Verify the IR after every pass. Always done in debug builds.
+    pub verify_ir: bool,
+    // Step limit for running input-free programs at compile time.
+    pub precompute: Option<usize>,
+    // Loop counts from a profiling run, used to pick a lowering per loop.
+    pub profile_use: Option<LoopProfile>,
+    pub backend: BackendOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precompute {
+    // The program reads input, so it was not attempted.
+    Skipped,
+    Done { steps: usize, output_len: usize },
+    Failed(Outcome),
+}
+
+pub struct Optimized {
+    pub ir: IR,
+    // Numbers of the loops in `ir`, see `tree::loop_ids`.
+    pub loop_ids: Vec<LoopId>,
+    // Remarks from every pass that ran, in the order they were made.
+    pub remarks: Vec<Remark>,
+    // Input that followed the program, see `ParseOptions::inline_input`.
+    pub input: Option<Vec<u8>>,
+}
+
+pub struct Compiled {
+    pub wasm: Vec<u8>,
+    pub precompute: Option<Precompute>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    Structure(StructureError),
+    InvalidIr {
+        pass: &'static str,
+        error: VerifyError,
+    },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Structure(e) => write!(f, "{}", e),
+            CompileError::InvalidIr { pass, error } => {
+                write!(f, "invalid IR after pass `{}`: {}", pass, error)
+            }
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+impl From<StructureError> for CompileError {
+    fn from(e: StructureError) -> CompileError {
+        CompileError::Structure(e)
+    }
+}
+
+type Pass<'a> = &'a dyn Fn(&Block, &mut Vec<Remark>) -> Block;
+
+// Names of the passes in the order they run. `combine` always runs on
+// source input, `unroll` runs when there is a profile to use and the rest
+// are enabled through `CompileOptions`.
+pub const PASS_NAMES: [&str; 6] = ["combine", "zero", "loops", "licm", "scan", "unroll"];
+
+fn check(opts: &CompileOptions, pass: &'static str, ir: &IR) -> Result<(), CompileError> {
+    if opts.verify_ir || cfg!(debug_assertions) {
+        verify(ir).map_err(|error| CompileError::InvalidIr { pass, error })?;
+    }
+    Ok(())
+}
+
+fn run_passes(
+    mut block: Block,
+    opts: &CompileOptions,
+    remarks: &mut Vec<Remark>,
+    after_pass: &mut dyn FnMut(&str, &Block),
+) -> Result<Block, CompileError> {
+    let profile = opts.profile_use.as_ref();
+    let scan = |block: &Block, remarks: &mut Vec<Remark>| scan_opt(block, profile, remarks);
+    let unroll = |block: &Block, remarks: &mut Vec<Remark>| match profile {
+        Some(profile) => unroll_hot_loops(block, profile, remarks),
+        None => block.clone(),
+    };
+    let passes: [(&'static str, bool, Pass); 5] = [
+        ("zero", opts.cell_zero_opt, &cell_zero),
+        ("loops", opts.loop_opt, &opt_simple_loops),
+        ("licm", opts.licm, &hoist_invariants),
+        ("scan", opts.scan_opt, &scan),
+        ("unroll", profile.is_some(), &unroll),
+    ];
+    for (name, enabled, pass) in passes {
+        if enabled {
+            block = pass(&block, remarks);
+            check(opts, name, &to_flat(&block))?;
+            after_pass(name, &block);
+        }
+    }
+
+    Ok(block)
+}
+
+pub fn optimize(program: &str, opts: &CompileOptions) -> Result<IR, CompileError> {
+    Ok(optimize_with(program, opts, &mut |_, _| ())?.ir)
+}
+
+// Like `optimize`, but hands the IR to `after_pass` after every pass and
+// returns the remarks the passes made.
+pub fn optimize_with(
+    program: &str,
+    opts: &CompileOptions,
+    after_pass: &mut dyn FnMut(&str, &Block),
+) -> Result<Optimized, CompileError> {
+    optimize_source(program, opts, true, after_pass)
+}
+
+// Like `optimize`, but without `combine`, so code the other passes leave
+// alone keeps an instruction per command for the debugger to step through.
+pub fn optimize_for_debugger(
+    program: &str,
+    opts: &CompileOptions,
+) -> Result<Optimized, CompileError> {
+    optimize_source(program, opts, false, &mut |_, _| ())
+}
+
+fn optimize_source(
+    program: &str,
+    opts: &CompileOptions,
+    combine: bool,
+    after_pass: &mut dyn FnMut(&str, &Block),
+) -> Result<Optimized, CompileError> {
+    let (program, input) = split_input(program, &opts.parse);
+    let tokens = tokens(program, &opts.parse);
+    let ir: IR = tokens.iter().map(|token| token.inst).collect();
+    check_depth(&ir)?;
+    let mut block = from_flat(&ir)?;
+    if combine {
+        block = inst_combine(&block);
+        check(opts, "combine", &to_flat(&block))?;
+        after_pass("combine", &block);
+    }
+
+    let mut remarks = vec![];
+    let block = run_passes(block, opts, &mut remarks, after_pass)?;
+    let spans = loop_spans(&tokens);
+    for remark in &mut remarks {
+        remark.span = spans.get(remark.loop_id).copied();
+    }
+
+    Ok(Optimized {
+        ir: to_flat(&block),
+        loop_ids: loop_ids(&block),
+        remarks,
+        input: input.map(|input| input.as_bytes().to_vec()),
+    })
+}
+
+// Runs the enabled passes on IR that didn't come from source, e.g. IR read
+// back from its textual form. Such IR is always verified, as nothing else
+// stops it holding operands the backend can't lower.
+pub fn optimize_ir(
+    ir: &IR,
+    opts: &CompileOptions,
+    after_pass: &mut dyn FnMut(&str, &Block),
+) -> Result<Optimized, CompileError> {
+    verify(ir).map_err(|error| CompileError::InvalidIr {
+        pass: "input",
+        error,
+    })?;
+    check_depth(ir)?;
+    let mut remarks = vec![];
+    let block = run_passes(from_flat(ir)?, opts, &mut remarks, after_pass)?;
+
+    Ok(Optimized {
+        ir: to_flat(&block),
+        loop_ids: loop_ids(&block),
+        remarks,
+        input: None,
+    })
+}
+
+// Profiling builds are never precomputed, as there would be nothing left to
+// count, and neither are builds with fuel, which must stop where the full
+// program would, or resumable and inspectable builds, whose hosts expect
+// their exports. Programs with `Inst::Debug` would lose their dumps.
+pub fn compile_ir(ir: &IR, opts: &CompileOptions) -> Compiled {
+    let precompute = match opts.precompute {
+        None => None,
+        Some(_)
+            if opts.backend.profile
+                || opts.backend.fuel.is_some()
+                || opts.backend.resumable
+                || opts.backend.inspect
+                || ir.contains(&Inst::Debug) =>
+        {
+            None
+        }
+        Some(_) if ir.contains(&Inst::In) && opts.backend.input.is_none() => {
+            Some(Precompute::Skipped)
+        }
+        Some(step_limit) => {
+            let mut machine = Machine::new(ir);
+            if let Some(input) = &opts.backend.input {
+                machine.input.extend(input);
+                machine.input_closed = true;
+            }
+            match machine.run(step_limit) {
+                Outcome::Finished => {
+                    let cell = machine.tape[machine.dp];
+                    return Compiled {
+                        wasm: create_output_wasm(&machine.output, machine.dp, cell),
+                        precompute: Some(Precompute::Done {
+                            steps: machine.steps,
+                            output_len: machine.output.len(),
+                        }),
+                    };
+                }
+                outcome => Some(Precompute::Failed(outcome)),
+            }
+        }
+    };
+
+    Compiled {
+        wasm: create_wasm(ir, &opts.backend),
+        precompute,
+    }
+}
+
+// Like `compile_ir`, but also embeds input that followed the program and
+// names the sites of profiling builds so the counts can be traced back to
+// the program.
+pub fn compile_optimized(optimized: &Optimized, opts: &CompileOptions) -> Compiled {
+    let mut compiled = match &optimized.input {
+        Some(input) => {
+            let mut opts = opts.clone();
+            opts.backend.input = Some(input.clone());
+            compile_ir(&optimized.ir, &opts)
+        }
+        None => compile_ir(&optimized.ir, opts),
+    };
+    if opts.backend.profile {
+        let sites = sites(&optimized.ir, &optimized.loop_ids);
+        compiled.wasm.extend(sites_section(&sites));
+    }
+    compiled
+}
+
 // #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 pub fn compile(
@@ -16,17 +292,147 @@ pub fn compile(
     do_cell_zero_opt: bool,
     do_simple_loop_opt: bool,
     do_scan_opt: bool,
-) -> Vec<u8> {
-    let mut ir = parse(&program.to_string());
-    ir = inst_combine(&ir);
-    if do_cell_zero_opt {
-        ir = cell_zero(&ir);
+) -> Result<Vec<u8>, JsError> {
+    let opts = CompileOptions {
+        parse: ParseOptions::default(),
+        cell_zero_opt: do_cell_zero_opt,
+        loop_opt: do_simple_loop_opt,
+        licm: false,
+        scan_opt: do_scan_opt,
+        verify_ir: false,
+        precompute: None,
+        profile_use: None,
+        backend: BackendOptions::default(),
+    };
+    Ok(compile_ir(&optimize(program, &opts)?, &opts).wasm)
+}
+
+// Options of `compile_with`, which the npm package fills in from its options
+// object. They mirror the flags of the CLI that don't name files.
+#[wasm_bindgen(js_name = CompileOptions)]
+#[derive(Debug, Clone, Default)]
+pub struct JsCompileOptions {
+    pub debug_hash: bool,
+    pub inline_input: bool,
+    pub cell_zero_opt: bool,
+    pub loop_opt: bool,
+    pub licm: bool,
+    pub scan_opt: bool,
+    pub reg_cache: bool,
+    pub resumable: bool,
+    pub inspect: bool,
+    pub dp_global: bool,
+    dialect: Dialect,
+    precompute: Option<usize>,
+    fuel: Option<u64>,
+    outline_loops: Option<usize>,
+}
+
+#[wasm_bindgen(js_class = CompileOptions)]
+impl JsCompileOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> JsCompileOptions {
+        JsCompileOptions::default()
+    }
+
+    pub fn set_dialect(&mut self, name: &str) -> Result<(), JsError> {
+        self.dialect = Dialect::from_name(name)
+            .ok_or_else(|| JsError::new(&format!("unknown dialect `{}`", name)))?;
+        Ok(())
+    }
+
+    pub fn set_precompute(&mut self, step_limit: usize) {
+        self.precompute = Some(step_limit);
     }
-    if do_simple_loop_opt {
-        ir = opt_simple_loops(&ir);
+
+    // Running out calls `env.out_of_fuel` rather than trapping.
+    pub fn set_fuel(&mut self, trips: u64) {
+        self.fuel = Some(trips);
     }
-    if do_scan_opt {
-        ir = scan_opt(&ir);
+
+    pub fn set_outline_loops(&mut self, size: usize) {
+        self.outline_loops = Some(size);
     }
-    create_wasm(&ir)
+}
+
+impl JsCompileOptions {
+    fn to_options(&self) -> CompileOptions {
+        CompileOptions {
+            parse: ParseOptions {
+                dialect: self.dialect,
+                debug: self.debug_hash,
+                inline_input: self.inline_input,
+            },
+            cell_zero_opt: self.cell_zero_opt,
+            loop_opt: self.loop_opt,
+            licm: self.licm,
+            scan_opt: self.scan_opt,
+            precompute: self.precompute,
+            backend: BackendOptions {
+                reg_cache: self.reg_cache,
+                fuel: self.fuel.map(|trips| Fuel {
+                    budget: FuelBudget::Fixed(trips),
+                    out_of_fuel: OutOfFuel::Import,
+                }),
+                resumable: self.resumable,
+                inspect: self.inspect,
+                outline: self.outline_loops,
+                dp_global: self.dp_global,
+                ..BackendOptions::default()
+            },
+            ..CompileOptions::default()
+        }
+    }
+}
+
+// Like `compile`, but takes every option and embeds inline input.
+#[wasm_bindgen]
+pub fn compile_with(program: &str, opts: &JsCompileOptions) -> Result<Vec<u8>, JsError> {
+    let opts = opts.to_options();
+    let optimized = optimize_with(program, &opts, &mut |_, _| ())?;
+    Ok(compile_optimized(&optimized, &opts).wasm)
+}
+
+// The IR of `program` as parsed, before any pass, in its textual form.
+#[wasm_bindgen]
+pub fn parsed_ir(program: &str, opts: &JsCompileOptions) -> String {
+    print_ir(&parse_with(program, &opts.to_options().parse))
+}
+
+// The IR as parsed and after every pass that ran, see `stages_to_json`.
+#[wasm_bindgen]
+pub fn pass_results(program: &str, opts: &JsCompileOptions) -> Result<String, JsError> {
+    let opts = opts.to_options();
+    let mut stages = vec![("parse", parse_with(program, &opts.parse))];
+    optimize_with(program, &opts, &mut |name, block| {
+        let name = PASS_NAMES.iter().find(|pass| **pass == name).unwrap();
+        stages.push((name, to_flat(block)));
+    })?;
+    Ok(stages_to_json(&stages))
+}
+
+// The module `compile_with` builds, in the text format.
+#[wasm_bindgen]
+pub fn compile_wat(program: &str, opts: &JsCompileOptions) -> Result<String, JsError> {
+    let wasm = compile_with(program, opts)?;
+    wasmprinter::print_bytes(wasm).map_err(|e| JsError::new(&e.to_string()))
+}
+
+// Runs the same passes as `compile` and returns their remarks as a JSON
+// array.
+#[wasm_bindgen]
+pub fn optimization_remarks(
+    program: &str,
+    do_cell_zero_opt: bool,
+    do_simple_loop_opt: bool,
+    do_scan_opt: bool,
+) -> Result<String, JsError> {
+    let opts = CompileOptions {
+        cell_zero_opt: do_cell_zero_opt,
+        loop_opt: do_simple_loop_opt,
+        scan_opt: do_scan_opt,
+        ..CompileOptions::default()
+    };
+    let optimized = optimize_with(program, &opts, &mut |_, _| ())?;
+    Ok(remarks::to_json(&optimized.remarks))
 }
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ir::{Inst, Offset};
+use crate::tree::Node;
+
+// How the data pointer moves over one trip through a loop body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Balanced,
+    Drift(Offset),
+    Unknown,
+}
+
+// Cells touched by a piece of code, relative to the pointer on entry.
+// `writes` counts the stores to each cell so callers can tell whether a
+// cell has a single writer. If `unknown` is set the pointer became
+// unknowable part way through and the sets only cover some of the accesses
+// before that point.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Effects {
+    pub reads: BTreeSet<Offset>,
+    pub writes: BTreeMap<Offset, usize>,
+    pub io: bool,
+    pub unknown: bool,
+}
+
+impl Effects {
+    pub fn reads(&self, off: Offset) -> bool {
+        self.unknown || self.reads.contains(&off)
+    }
+
+    pub fn writes(&self, off: Offset) -> bool {
+        self.unknown || self.writes.contains_key(&off)
+    }
+
+    pub fn writers(&self, off: Offset) -> usize {
+        self.writes.get(&off).copied().unwrap_or(0)
+    }
+
+    fn read(&mut self, off: Offset) {
+        self.reads.insert(off);
+    }
+
+    fn write(&mut self, off: Offset) {
+        *self.writes.entry(off).or_insert(0) += 1;
+    }
+
+    fn merge(&mut self, inner: &Effects, base: Offset) {
+        self.io |= inner.io;
+        // The sets of code that lost track of the pointer are incomplete
+        // anyway, and copying them would grow them at every enclosing loop.
+        if inner.unknown {
+            self.unknown = true;
+            return;
+        }
+        for off in &inner.reads {
+            self.read(base + off);
+        }
+        for (off, ct) in &inner.writes {
+            *self.writes.entry(base + off).or_insert(0) += ct;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopInfo {
+    pub motion: Motion,
+    pub effects: Effects,
+}
+
+impl LoopInfo {
+    // True when every access of the loop is at a known offset from the cell
+    // it was entered on, on every trip.
+    pub fn is_exact(&self) -> bool {
+        self.motion == Motion::Balanced && !self.effects.unknown
+    }
+}
+
+// What a loop body does when entered with the pointer at 0, and where it
+// leaves the pointer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BodyInfo {
+    pub effects: Effects,
+    pub end: Option<Offset>,
+}
+
+// Analyzes the body of a `Node::Loop`, including the test of the cell the
+// loop is entered on. Accesses of a drifting loop are relative to the start
+// of the trip.
+pub fn analyze_loop(body: &[Node]) -> LoopInfo {
+    loop_info(&analyze_body(body))
+}
+
+pub fn loop_info(body: &BodyInfo) -> LoopInfo {
+    let mut effects = body.effects.clone();
+    effects.read(0);
+    let motion = match body.end {
+        Some(0) => Motion::Balanced,
+        Some(stride) => Motion::Drift(stride),
+        None => Motion::Unknown,
+    };
+
+    LoopInfo { motion, effects }
+}
+
+pub fn analyze_body(body: &[Node]) -> BodyInfo {
+    let mut effects = Effects::default();
+    let end = analyze_block(body, 0, &mut effects);
+    BodyInfo { effects, end }
+}
+
+// Like `analyze_body`, but see `analyze_block_with`.
+pub fn analyze_body_with(body: &[Node], inner: &[BodyInfo]) -> BodyInfo {
+    let mut effects = Effects::default();
+    let end = analyze_block_with(body, 0, &mut effects, inner);
+    BodyInfo { effects, end }
+}
+
+// Records the effects of `block` entered with the pointer at `base` and
+// returns where the pointer ends up, or `None` if that can't be known.
+pub fn analyze_block(block: &[Node], base: Offset, effects: &mut Effects) -> Option<Offset> {
+    walk(block, base, effects, &mut |body, dp, effects| {
+        analyze_block(body, dp, effects)
+    })
+}
+
+// Like `analyze_block`, but takes what the bodies of the loops directly in
+// `block` do from `inner`, in the order the loops appear, instead of
+// walking them again. Passes that rebuild the tree bottom-up keep these
+// around, so each body is walked once however deeply the loops nest.
+pub fn analyze_block_with(
+    block: &[Node],
+    base: Offset,
+    effects: &mut Effects,
+    inner: &[BodyInfo],
+) -> Option<Offset> {
+    let mut inner = inner.iter();
+    walk(block, base, effects, &mut |_, dp, effects| {
+        let info = inner.next().expect("no analysis for an inner loop");
+        effects.merge(&info.effects, dp);
+        info.end.map(|end| dp + end)
+    })
+}
+
+// Records the effects of the body of an inner loop entered with the pointer
+// at the given offset, and returns where the pointer ends up.
+type Inner<'a> = &'a mut dyn FnMut(&[Node], Offset, &mut Effects) -> Option<Offset>;
+
+// Walks `block`, leaving the bodies of inner loops to `inner`.
+fn walk(block: &[Node], base: Offset, effects: &mut Effects, inner: Inner) -> Option<Offset> {
+    let mut dp = base;
+    for node in block {
+        match *node {
+            Node::Inst(ins) => match ins {
+                Inst::Right(ct) => dp += ct as i32,
+                Inst::Left(ct) => dp -= ct as i32,
+                Inst::Add(_, off) => {
+                    effects.read(dp + off);
+                    effects.write(dp + off);
+                }
+                Inst::Set(_, off) => effects.write(dp + off),
+                Inst::AddFrom(_, off) | Inst::SubFrom(_, off) => {
+                    effects.read(dp);
+                    effects.read(dp + off);
+                    effects.write(dp + off);
+                }
+                Inst::In => {
+                    effects.io = true;
+                    effects.write(dp);
+                }
+                Inst::Out => {
+                    effects.io = true;
+                    effects.read(dp);
+                }
+                Inst::Scan(_) => {
+                    effects.read(dp);
+                    effects.unknown = true;
+                    return None;
+                }
+                // The host may look at any cell, so no access can be moved
+                // across it, and the same goes for procedures and whatever
+                // looks at the tape once the program ends.
+                Inst::Debug | Inst::Call | Inst::Exit => {
+                    effects.io = true;
+                    effects.unknown = true;
+                    return None;
+                }
+                Inst::Save => effects.read(dp),
+                Inst::Restore => effects.write(dp),
+                Inst::Bitwise(_) => {
+                    effects.read(dp);
+                    effects.write(dp);
+                }
+                Inst::LoopStart
+                | Inst::LoopEnd
+                | Inst::SimpleLoopStart(_)
+                | Inst::SimpleLoopEnd
+                | Inst::ProcStart
+                | Inst::ProcEnd => {
+                    unreachable!("{:?} in a block", ins)
+                }
+            },
+            Node::Loop(_, ref body) | Node::SimpleLoop(_, _, ref body) => {
+                match *node {
+                    Node::SimpleLoop(_, off, _) => effects.read(dp + off),
+                    _ => effects.read(dp),
+                }
+                if inner(body, dp, effects) != Some(dp) {
+                    effects.unknown = true;
+                    return None;
+                }
+            }
+            // Defining a procedure reads the cell holding its number and
+            // counts as I/O so the definition is never dropped.
+            Node::Proc(_) => {
+                effects.io = true;
+                effects.read(dp);
+            }
+        }
+    }
+
+    Some(dp)
+}
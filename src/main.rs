@@ -1,22 +1,64 @@
-use backend::create_wasm;
-use clap::Parser;
-use ir::{cell_zero, inst_combine, opt_simple_loops, parse, scan_opt};
-use ir::{Inst, IR};
+use bf_wasm_compiler::backend::{BackendOptions, Fuel, FuelBudget, OutOfFuel};
+use bf_wasm_compiler::debugger::{unescape, Debugger, Reply};
+use bf_wasm_compiler::dot::to_dot;
+use bf_wasm_compiler::frontend::{Dialect, ParseOptions, DIALECT_NAMES};
+use bf_wasm_compiler::interp::{Outcome, Trap};
+use bf_wasm_compiler::profile::{loop_profile, parse_profile, report};
+use bf_wasm_compiler::text::{parse_ir, print_ir};
+use bf_wasm_compiler::tree::{from_flat, to_flat, Block};
+use bf_wasm_compiler::{
+    compile_optimized, optimize_for_debugger, optimize_ir, optimize_with, CompileOptions,
+    Precompute, PASS_NAMES,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::error::Error;
 use std::fs;
+use std::io::{self, Write};
 use std::path::PathBuf;
-
-mod backend;
-mod ir;
+use std::process;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    #[arg(short, long, value_name = "FILE")]
-    bf_source: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["from_ir", "profile_report"]
+    )]
+    bf_source: Option<PathBuf>,
+
+    /// Compile textual IR instead of bf source, skipping the `combine` pass
+    #[arg(long, value_name = "FILE", conflicts_with = "bf_source")]
+    from_ir: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present = "profile_report"
+    )]
+    output: Option<PathBuf>,
+
+    /// What to write to the output file
+    #[arg(long, value_enum, default_value_t = Emit::Wasm)]
+    emit: Emit,
 
-    #[arg(short, long, value_name = "FILE")]
-    output: PathBuf,
+    /// The language the source is written in
+    #[arg(long, default_value = "bf", value_parser = DIALECT_NAMES)]
+    dialect: String,
+
+    /// Make `#` dump the data pointer and the cells around it through `env.debug`
+    #[arg(long)]
+    debug_hash: bool,
+
+    /// Treat the text after the first `!` outside a command as the program's input
+    #[arg(long)]
+    inline_input: bool,
 
     #[arg(short, long)]
     loop_opt: bool,
@@ -24,52 +66,285 @@ struct Cli {
     #[arg(short, long)]
     scan_opt: bool,
 
+    /// Move stores that don't change between loop trips out of the loop
+    #[arg(long)]
+    licm: bool,
+
     #[arg(short, long)]
     cell_zero_opt: bool,
 
     #[arg(short, long)]
     print_ir: bool,
+
+    /// Print the IR after the given passes
+    #[arg(long, value_name = "PASS", value_delimiter = ',', value_parser = PASS_NAMES)]
+    print_ir_after: Vec<String>,
+
+    /// Explain which loops each pass optimized or left alone, and why
+    #[arg(long)]
+    remarks: bool,
+
+    /// Check the IR for well-formedness after every pass
+    #[arg(long)]
+    verify_ir: bool,
+
+    /// Keep cells in wasm locals within straight-line code
+    #[arg(long)]
+    reg_cache: bool,
+
+    /// Run programs that never read input at compile time and emit only their output
+    #[arg(long)]
+    precompute: bool,
+
+    /// Count loop trips and I/O in the compiled program; the runner saves the counts on exit
+    #[arg(long, conflicts_with = "precompute")]
+    profile: bool,
+
+    /// Print the hot loops of a profile saved by the runner for a program built with --profile,
+    /// with their place in the source if one is given, instead of compiling
+    #[arg(long, value_name = "PROFILE", conflicts_with = "from_ir")]
+    profile_report: Option<PathBuf>,
+
+    /// Choose scans and unroll hot loops based on a profile saved by the runner
+    #[arg(long, value_name = "PROFILE")]
+    profile_use: Option<PathBuf>,
+
+    /// Stop the program after this many loop trips, or `param` to take the budget as an
+    /// argument to `main`
+    #[arg(long, value_name = "TRIPS", value_parser = parse_fuel, conflicts_with = "precompute")]
+    fuel: Option<FuelBudget>,
+
+    /// Call `env.out_of_fuel` and return from `main` instead of trapping when fuel runs out
+    #[arg(long, requires = "fuel")]
+    out_of_fuel_import: bool,
+
+    /// Return from `main` when the program needs input; the host continues it by calling
+    /// `resume` with the next byte. Input read inside pbrain procedures still blocks on
+    /// `env.read`
+    #[arg(long, conflicts_with = "precompute")]
+    resumable: bool,
+
+    /// Export the memory and `get_dp`, `get_cell` and `tape_len` to inspect the tape after a run
+    #[arg(long, conflicts_with = "precompute")]
+    inspect: bool,
+
+    /// Move loops of more than SIZE IR instructions into wasm functions of their own
+    #[arg(long, value_name = "SIZE")]
+    outline_loops: Option<usize>,
+
+    /// Keep the data pointer in a global instead of a local passed between functions
+    #[arg(long)]
+    dp_global: bool,
+
+    /// Maximum number of IR instructions executed by --precompute
+    #[arg(long, value_name = "STEPS", default_value_t = 100_000_000)]
+    step_limit: usize,
 }
 
-// TODO make this look like John's IR output
-fn print_ir(ir: &IR) -> () {
-    let mut loop_nest = 0;
-    for i in ir.to_vec() {
-        if i == Inst::LoopEnd {
-            loop_nest -= 1;
-        }
-        for _ in 0..loop_nest {
-            print!("\t");
+#[derive(Subcommand)]
+enum Command {
+    /// Step through a program on the IR interpreter
+    Debug(DebugArgs),
+}
+
+#[derive(Args)]
+struct DebugArgs {
+    /// The bf source to debug; `#` in it acts as a breakpoint
+    program: PathBuf,
+
+    #[arg(short, long)]
+    loop_opt: bool,
+
+    #[arg(short, long)]
+    scan_opt: bool,
+
+    #[arg(long)]
+    licm: bool,
+
+    #[arg(short, long)]
+    cell_zero_opt: bool,
+
+    /// Input for the program, with \n for a newline
+    #[arg(long, value_name = "TEXT")]
+    input: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    Wasm,
+    /// Loop structure and basic blocks of the optimized IR as a Graphviz graph
+    Dot,
+}
+
+fn parse_fuel(arg: &str) -> Result<FuelBudget, String> {
+    match arg {
+        "param" => Ok(FuelBudget::Param),
+        trips => trips
+            .parse()
+            .map(FuelBudget::Fixed)
+            .map_err(|_| format!("expected a number of trips or `param`, got `{}`", trips)),
+    }
+}
+
+fn report_precompute(result: &Precompute) {
+    match result {
+        Precompute::Skipped => eprintln!("precompute: program reads input, compiling normally"),
+        Precompute::Done { steps, output_len } => eprintln!(
+            "precompute: finished in {} steps, emitting {} bytes of output",
+            steps, output_len
+        ),
+        Precompute::Failed(Outcome::StepLimit) => {
+            eprintln!("precompute: step limit reached, compiling normally")
         }
-        println!("{:?}", i);
-        if i == Inst::LoopStart {
-            loop_nest += 1;
+        Precompute::Failed(Outcome::Trapped(Trap::PointerOutOfBounds(addr))) => eprintln!(
+            "precompute: data pointer left the tape (address {}), compiling normally",
+            addr
+        ),
+        Precompute::Failed(Outcome::Trapped(Trap::UndefinedProcedure(cell))) => eprintln!(
+            "precompute: called procedure {}, which isn't defined, compiling normally",
+            cell
+        ),
+        Precompute::Failed(outcome) => {
+            eprintln!("precompute: {:?}, compiling normally", outcome)
         }
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-    let program: String = fs::read_to_string(cli.bf_source)?;
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let profile_use = match &cli.profile_use {
+        Some(path) => Some(loop_profile(&parse_profile(&fs::read_to_string(path)?)?)),
+        None => None,
+    };
+    let opts = CompileOptions {
+        parse: ParseOptions {
+            dialect: Dialect::from_name(&cli.dialect).unwrap(),
+            debug: cli.debug_hash,
+            inline_input: cli.inline_input,
+        },
+        cell_zero_opt: cli.cell_zero_opt,
+        loop_opt: cli.loop_opt,
+        licm: cli.licm,
+        scan_opt: cli.scan_opt,
+        verify_ir: cli.verify_ir,
+        precompute: cli.precompute.then_some(cli.step_limit),
+        profile_use,
+        backend: BackendOptions {
+            reg_cache: cli.reg_cache,
+            profile: cli.profile,
+            fuel: cli.fuel.map(|budget| Fuel {
+                budget,
+                out_of_fuel: match cli.out_of_fuel_import {
+                    true => OutOfFuel::Import,
+                    false => OutOfFuel::Trap,
+                },
+            }),
+            resumable: cli.resumable,
+            inspect: cli.inspect,
+            input: None,
+            outline: cli.outline_loops,
+            dp_global: cli.dp_global,
+        },
+    };
 
-    let mut ir = parse(&program);
-    ir = inst_combine(&ir);
-    if cli.cell_zero_opt {
-        ir = cell_zero(&ir);
-    }
-    if cli.loop_opt {
-        ir = opt_simple_loops(&ir);
+    if let Some(path) = &cli.profile_report {
+        let profile = parse_profile(&fs::read_to_string(path)?)?;
+        let source = match &cli.bf_source {
+            Some(path) => Some(fs::read_to_string(path)?),
+            None => None,
+        };
+        let program = source.as_deref().map(|source| (source, &opts.parse));
+        print!("{}", report(&profile, program, 20));
+        return Ok(());
     }
-    if cli.scan_opt {
-        ir = scan_opt(&ir);
+
+    let mut after_pass = |name: &str, block: &Block| {
+        if cli.print_ir_after.iter().any(|pass| pass == name) {
+            println!("# after {}", name);
+            print!("{}", print_ir(&to_flat(block)));
+        }
+    };
+    let optimized = match (&cli.bf_source, &cli.from_ir) {
+        (Some(path), _) => optimize_with(&fs::read_to_string(path)?, &opts, &mut after_pass)?,
+        (None, Some(path)) => {
+            let ir = parse_ir(&fs::read_to_string(path)?)?;
+            optimize_ir(&ir, &opts, &mut after_pass)?
+        }
+        (None, None) => unreachable!(),
+    };
+
+    if cli.remarks {
+        let file = cli.bf_source.as_ref().or(cli.from_ir.as_ref()).unwrap();
+        for remark in &optimized.remarks {
+            eprintln!("{}:{}", file.display(), remark);
+        }
     }
 
     if cli.print_ir {
-        print_ir(&ir);
+        print!("{}", print_ir(&optimized.ir));
+    }
+
+    let output = cli.output.unwrap();
+    if cli.emit == Emit::Dot {
+        fs::write(output, to_dot(&from_flat(&optimized.ir)?))?;
+        return Ok(());
     }
 
-    let wasm_bytes = create_wasm(&ir);
+    let compiled = compile_optimized(&optimized, &opts);
+    if let Some(result) = &compiled.precompute {
+        report_precompute(result);
+    }
 
-    fs::write(cli.output, wasm_bytes)?;
+    fs::write(output, compiled.wasm)?;
     Ok(())
 }
+
+fn debug(args: DebugArgs) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(&args.program)?;
+    let opts = CompileOptions {
+        cell_zero_opt: args.cell_zero_opt,
+        loop_opt: args.loop_opt,
+        licm: args.licm,
+        scan_opt: args.scan_opt,
+        ..CompileOptions::default()
+    };
+    let optimized = optimize_for_debugger(&source, &opts)?;
+    let mut debugger = Debugger::new(&source, &optimized);
+    if let Some(input) = &args.input {
+        debugger.queue_input(&unescape(input));
+    }
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    loop {
+        print!("(bfdb) ");
+        stdout.flush()?;
+        line.clear();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let reply = debugger.execute(&line);
+        let output = debugger.take_output();
+        stdout.write_all(output)?;
+        if output.last().is_some_and(|byte| *byte != b'\n') {
+            println!();
+        }
+        match reply {
+            Reply::Text(text) if text.is_empty() => (),
+            Reply::Text(text) => println!("{}", text.trim_end()),
+            Reply::Quit => return Ok(()),
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::Debug(args)) => debug(args),
+        None => run(cli),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
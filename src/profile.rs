@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+
+use wasm_encoder::{CustomSection, Section};
+
+use crate::backend::is_profile_site;
+use crate::frontend::{split_input, tokens, ParseOptions};
+use crate::ir::{Inst, IR};
+use crate::remarks::{loop_spans, Span};
+use crate::tree::LoopId;
+
+// Name of the custom section listing the sites of a profiling build, one
+// per line in profile table order.
+pub const SITES_SECTION: &str = "bf-profile-sites";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SiteKind {
+    Loop,
+    SimpleLoop,
+    Out,
+    In,
+}
+
+// An instruction with a profile counter. Loops are named by their `LoopId`
+// and I/O by its index among the `.` or `,` of the program, so a profile can
+// be used with a build of the same program with other options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Site {
+    pub kind: SiteKind,
+    pub id: usize,
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            SiteKind::Loop => "loop",
+            SiteKind::SimpleLoop => "simple",
+            SiteKind::Out => "out",
+            SiteKind::In => "in",
+        };
+        write!(f, "{} {}", kind, self.id)
+    }
+}
+
+// The two counters of a profile table entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Count {
+    pub reached: u64,
+    // Trips through the body, only counted for loops.
+    pub body: u64,
+}
+
+impl Count {
+    pub fn trips_per_entry(&self) -> f64 {
+        match self.reached {
+            0 => 0.0,
+            n => self.body as f64 / n as f64,
+        }
+    }
+}
+
+// Counts of each loop, preferring the loop itself over the simple loop
+// guarding it when both were profiled.
+pub type LoopProfile = HashMap<LoopId, Count>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "profile line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ProfileError {}
+
+// Lists the profile sites of `ir` in table order. `loop_ids` numbers the
+// loops of `ir` as `tree::loop_ids` does.
+pub fn sites(ir: &IR, loop_ids: &[LoopId]) -> Vec<Site> {
+    let mut loops = loop_ids.iter();
+    let (mut writes, mut reads) = (0, 0);
+
+    let mut sites = vec![];
+    for ins in ir.iter().filter(|ins| is_profile_site(ins)) {
+        // Passes never add, drop or reorder I/O, so the n-th `Out` is the
+        // n-th `.` of the source.
+        let (kind, id) = match ins {
+            Inst::LoopStart => (SiteKind::Loop, *loops.next().unwrap()),
+            Inst::SimpleLoopStart(_) => (SiteKind::SimpleLoop, *loops.next().unwrap()),
+            Inst::Out => {
+                writes += 1;
+                (SiteKind::Out, writes - 1)
+            }
+            _ => {
+                reads += 1;
+                (SiteKind::In, reads - 1)
+            }
+        };
+        sites.push(Site { kind, id });
+    }
+
+    sites
+}
+
+// Encodes `sites` as a custom section to append to a profiling build.
+pub fn sites_section(sites: &[Site]) -> Vec<u8> {
+    let mut names = String::new();
+    for site in sites {
+        let _ = writeln!(names, "{}", site);
+    }
+    let mut bytes = vec![];
+    CustomSection {
+        name: SITES_SECTION.into(),
+        data: names.as_bytes().into(),
+    }
+    .append_to(&mut bytes);
+    bytes
+}
+
+// Reads a profile written by the runner: one line per site holding its name
+// and its `reached` and `body` counters, e.g. `loop 3 12 480`.
+pub fn parse_profile(text: &str) -> Result<Vec<(Site, Count)>, ProfileError> {
+    let mut profile = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let err = |message: &str| ProfileError {
+            line: idx + 1,
+            message: message.to_string(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (kind, numbers) = match words[..] {
+            [] => continue,
+            [kind, ref numbers @ ..] if numbers.len() == 3 => (kind, numbers),
+            _ => return Err(err("expected a site kind, its id and two counters")),
+        };
+        let kind = match kind {
+            "loop" => SiteKind::Loop,
+            "simple" => SiteKind::SimpleLoop,
+            "out" => SiteKind::Out,
+            "in" => SiteKind::In,
+            kind => return Err(err(&format!("unknown site kind `{}`", kind))),
+        };
+        let numbers: Vec<u64> = numbers
+            .iter()
+            .map(|word| {
+                word.parse()
+                    .map_err(|_| err("ids and counters must be integers"))
+            })
+            .collect::<Result<_, _>>()?;
+        profile.push((
+            Site {
+                kind,
+                id: numbers[0] as usize,
+            },
+            Count {
+                reached: numbers[1],
+                body: numbers[2],
+            },
+        ));
+    }
+
+    Ok(profile)
+}
+
+pub fn loop_profile(profile: &[(Site, Count)]) -> LoopProfile {
+    let mut loops = LoopProfile::new();
+    for (site, count) in profile {
+        match site.kind {
+            SiteKind::Loop => {
+                loops.insert(site.id, *count);
+            }
+            SiteKind::SimpleLoop => {
+                loops.entry(site.id).or_insert(*count);
+            }
+            SiteKind::Out | SiteKind::In => (),
+        }
+    }
+    loops
+}
+
+// Lists the `limit` loops whose bodies ran most often, then the I/O counts.
+// Positions are given when the source of the profiled program is known.
+pub fn report(
+    profile: &[(Site, Count)],
+    program: Option<(&str, &ParseOptions)>,
+    limit: usize,
+) -> String {
+    let spans = program
+        .map(|(program, opts)| loop_spans(&tokens(split_input(program, opts).0, opts)))
+        .unwrap_or_default();
+    let mut out = String::new();
+    let mut loops: Vec<&(Site, Count)> = profile
+        .iter()
+        .filter(|(site, _)| matches!(site.kind, SiteKind::Loop | SiteKind::SimpleLoop))
+        .collect();
+    loops.sort_by_key(|(_, count)| std::cmp::Reverse(count.body));
+
+    out.push_str("hot loops:\n");
+    for (site, count) in loops.iter().take(limit) {
+        let _ = write!(
+            out,
+            "{:>14} trips {:>12} entries {:>10.1} avg  {}",
+            count.body,
+            count.reached,
+            count.trips_per_entry(),
+            site
+        );
+        let _ = match spans.get(site.id) {
+            Some(Span { start, end }) => {
+                writeln!(out, " at {}:{}-{}:{}", start.0, start.1, end.0, end.1)
+            }
+            None => writeln!(out),
+        };
+    }
+
+    let io = |kind: SiteKind| -> u64 {
+        profile
+            .iter()
+            .filter(|(site, _)| site.kind == kind)
+            .map(|(_, count)| count.reached)
+            .sum()
+    };
+    let _ = writeln!(
+        out,
+        "output: {} bytes, input: {} bytes",
+        io(SiteKind::Out),
+        io(SiteKind::In)
+    );
+
+    out
+}
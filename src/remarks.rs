@@ -0,0 +1,141 @@
+use std::fmt;
+use std::fmt::Write;
+
+use crate::frontend::Token;
+use crate::ir::Inst;
+use crate::tree::LoopId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemarkKind {
+    Applied,
+    Missed,
+}
+
+impl fmt::Display for RemarkKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemarkKind::Applied => write!(f, "applied"),
+            RemarkKind::Missed => write!(f, "missed"),
+        }
+    }
+}
+
+// Line and column (both starting at 1) of a loop's `[` and `]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+// What a pass did, or didn't do, to a loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remark {
+    pub pass: &'static str,
+    pub kind: RemarkKind,
+    pub loop_id: LoopId,
+    // Only known when compiling from source.
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Remark {
+    pub fn applied(pass: &'static str, loop_id: LoopId, message: String) -> Remark {
+        Remark {
+            pass,
+            kind: RemarkKind::Applied,
+            loop_id,
+            span: None,
+            message,
+        }
+    }
+
+    pub fn missed(pass: &'static str, loop_id: LoopId, message: String) -> Remark {
+        Remark {
+            kind: RemarkKind::Missed,
+            ..Remark::applied(pass, loop_id, message)
+        }
+    }
+}
+
+impl fmt::Display for Remark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(Span { start, end }) => {
+                write!(f, "{}:{}-{}:{}: ", start.0, start.1, end.0, end.1)?
+            }
+            None => write!(f, "loop {}: ", self.loop_id)?,
+        }
+        write!(f, "{}: {}: {}", self.pass, self.kind, self.message)
+    }
+}
+
+// Spans of the loops in the commands of a program, indexed by `LoopId`.
+// Loops that are never closed get no span, which only matters for programs
+// that fail to compile anyway.
+pub fn loop_spans(tokens: &[Token]) -> Vec<Span> {
+    let mut spans: Vec<Span> = vec![];
+    let mut open: Vec<usize> = vec![];
+    for token in tokens {
+        let pos = (token.line, token.column);
+        match token.inst {
+            Inst::LoopStart => {
+                open.push(spans.len());
+                spans.push(Span {
+                    start: pos,
+                    end: pos,
+                });
+            }
+            Inst::LoopEnd => {
+                if let Some(id) = open.pop() {
+                    spans[id].end = pos;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    spans
+}
+
+pub fn to_json(remarks: &[Remark]) -> String {
+    let mut out = String::from("[");
+    for (idx, remark) in remarks.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"pass\":\"{}\",\"kind\":\"{}\",\"loop\":{},\"span\":",
+            remark.pass, remark.kind, remark.loop_id
+        );
+        let _ = match remark.span {
+            Some(Span { start, end }) => write!(
+                out,
+                "{{\"start\":{{\"line\":{},\"column\":{}}},\"end\":{{\"line\":{},\"column\":{}}}}}",
+                start.0, start.1, end.0, end.1
+            ),
+            None => write!(out, "null"),
+        };
+        out.push_str(",\"message\":");
+        json_string(&mut out, &remark.message);
+        out.push('}');
+    }
+    out.push(']');
+
+    out
+}
+
+pub(crate) fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
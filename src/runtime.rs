@@ -0,0 +1,203 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use wasmi::{Caller, Engine, Extern, Linker, Module, Store, Val};
+
+use crate::backend::resume::NEEDS_INPUT;
+use crate::interp::{dump_tape, DP_START, TAPE_LEN};
+use crate::{compile_optimized, optimize_with, CompileError, CompileOptions};
+
+// Runs compiled modules in an embedded engine, doing what `wasm_runner.js`
+// does for the imports: `env.write` and `env.read` go to a `Write` and a
+// `Read`, and `env.debug` dumps the tape into the output. Resumable builds
+// are resumed until they finish, and builds taking their fuel get all
+// there is.
+
+// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    // `debug_terminate` reported the cell the program ended on, numbered
+    // from `DP_START`, and its value.
+    Finished { cell: i32, value: u8 },
+    // `env.out_of_fuel` was called. Builds that trap instead fail with
+    // `RunError::Trap`.
+    OutOfFuel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub output: Vec<u8>,
+    pub exit: Exit,
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Compile(CompileError),
+    // The engine rejected the module.
+    Instantiate(wasmi::Error),
+    Trap(wasmi::Error),
+    Io(io::Error),
+    // `main` returned without ending the program.
+    Unfinished,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Compile(e) => write!(f, "{}", e),
+            RunError::Instantiate(e) => write!(f, "can't instantiate module: {}", e),
+            RunError::Trap(e) => write!(f, "program trapped: {}", e),
+            RunError::Io(e) => write!(f, "{}", e),
+            RunError::Unfinished => write!(f, "program returned without terminating"),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+impl From<CompileError> for RunError {
+    fn from(e: CompileError) -> RunError {
+        RunError::Compile(e)
+    }
+}
+
+struct Host<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    exit: Option<Exit>,
+    // The first failed read or write, which stops the program.
+    error: Option<io::Error>,
+}
+
+impl Host<'_> {
+    // Reads the next byte of input, or zero at its end.
+    fn read(&mut self) -> Result<i32, wasmi::Error> {
+        let mut byte = [0];
+        match self.input.read_exact(&mut byte) {
+            Ok(()) => Ok(byte[0] as i32),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), wasmi::Error> {
+        match self.output.write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn fail<T>(&mut self, e: io::Error) -> Result<T, wasmi::Error> {
+        let error = wasmi::Error::new(e.to_string());
+        self.error = Some(e);
+        Err(error)
+    }
+}
+
+fn linker<'a>(engine: &Engine) -> Linker<Host<'a>> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap("env", "write", |mut caller: Caller<Host>, byte: i32| {
+            caller.data_mut().write(&[byte as u8])
+        })
+        .unwrap()
+        .func_wrap("env", "read", |mut caller: Caller<Host>| {
+            caller.data_mut().read()
+        })
+        .unwrap()
+        .func_wrap(
+            "env",
+            "debug_terminate",
+            |mut caller: Caller<Host>, addr: i32, value: i32| {
+                caller.data_mut().exit = Some(Exit::Finished {
+                    cell: addr - DP_START as i32,
+                    value: value as u8,
+                });
+            },
+        )
+        .unwrap()
+        .func_wrap("env", "out_of_fuel", |mut caller: Caller<Host>| {
+            caller.data_mut().exit = Some(Exit::OutOfFuel);
+        })
+        .unwrap()
+        // Shows the 16 cells around the one at `addr`.
+        .func_wrap("env", "debug", |mut caller: Caller<Host>, addr: i32| {
+            let memory = caller.get_export("memory").and_then(Extern::into_memory);
+            let memory = memory.expect("modules importing `debug` export their memory");
+            let tape = &memory.data(&caller)[DP_START..TAPE_LEN];
+            let cell = addr as i64 - DP_START as i64;
+            let start = (cell - 8).max(0) as usize;
+            let dump = format!("\n# cell {}\n{}", cell, dump_tape(tape, cell, start, 16));
+            caller.data_mut().write(dump.as_bytes())
+        })
+        .unwrap();
+    linker
+}
+
+// Runs a module built by `create_wasm` or `create_output_wasm`.
+pub fn run_wasm(
+    wasm: &[u8],
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<Exit, RunError> {
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm).map_err(RunError::Instantiate)?;
+    let host = Host {
+        input,
+        output,
+        exit: None,
+        error: None,
+    };
+    let mut store = Store::new(&engine, host);
+    let instance = linker(&engine)
+        .instantiate_and_start(&mut store, &module)
+        .map_err(RunError::Instantiate)?;
+    let main = instance.get_func(&store, "main").unwrap();
+    let resume = instance.get_func(&store, "resume");
+
+    let args = match main.ty(&store).params().len() {
+        0 => vec![],
+        _ => vec![Val::I64(i64::MAX)],
+    };
+    let mut status = [Val::I32(0)];
+    let results = match resume {
+        Some(_) => &mut status[..],
+        None => &mut [],
+    };
+    let mut result = main.call(&mut store, &args, results);
+    while let (Ok(()), Some(resume)) = (&result, resume) {
+        if status[0].i32() != Some(NEEDS_INPUT) {
+            break;
+        }
+        result = match store.data_mut().read() {
+            Ok(byte) => resume.call(&mut store, &[Val::I32(byte)], &mut status),
+            Err(e) => Err(e),
+        };
+    }
+
+    let host = store.into_data();
+    if let Some(e) = host.error {
+        return Err(RunError::Io(e));
+    }
+    // Helpers trap once they ran out of fuel.
+    match (host.exit, result) {
+        (Some(Exit::OutOfFuel), _) => Ok(Exit::OutOfFuel),
+        (_, Err(e)) => Err(RunError::Trap(e)),
+        (Some(exit), Ok(())) => Ok(exit),
+        (None, Ok(())) => Err(RunError::Unfinished),
+    }
+}
+
+// Compiles `source` and runs it on `input`.
+pub fn run_program(
+    source: &str,
+    opts: &CompileOptions,
+    mut input: &[u8],
+) -> Result<Output, RunError> {
+    let optimized = optimize_with(source, opts, &mut |_, _| ())?;
+    let wasm = compile_optimized(&optimized, opts).wasm;
+    let mut output = vec![];
+    let exit = run_wasm(&wasm, &mut input, &mut output)?;
+    Ok(Output { output, exit })
+}
@@ -0,0 +1,283 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::ir::{BitOp, Inst, IR};
+use crate::remarks::json_string;
+
+// Textual form of the IR, one instruction per line:
+//
+//     add -1 @2       # cell at offset 2 -= 1
+//     set 0           # offsets default to 0
+//     addfrom 3 @-1   # cell at offset -1 += cell * 3
+//     loop {
+//         right 2
+//     }
+//     simple @1 {     # SimpleLoopStart(1)
+//     }
+//     scan -2
+//     proc {          # ProcStart
+//         xor         # bitwise ops name their BitOp
+//     }
+//
+// Everything after a `#` is a comment.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for TextError {}
+
+pub fn print_ir(ir: &IR) -> String {
+    let mut out = String::new();
+    let mut loop_nest = 0;
+    for ins in ir {
+        if matches!(ins, Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd) {
+            loop_nest -= 1;
+        }
+        for _ in 0..loop_nest {
+            out.push_str("    ");
+        }
+        let _ = match *ins {
+            Inst::Add(d, off) => write!(out, "add {}{}", d, at(off)),
+            Inst::AddFrom(ct, off) => write!(out, "addfrom {}{}", ct, at(off)),
+            Inst::SubFrom(ct, off) => write!(out, "subfrom {}{}", ct, at(off)),
+            Inst::Left(ct) => write!(out, "left {}", ct),
+            Inst::Right(ct) => write!(out, "right {}", ct),
+            Inst::In => write!(out, "in"),
+            Inst::Out => write!(out, "out"),
+            Inst::LoopStart => write!(out, "loop {{"),
+            Inst::SimpleLoopStart(off) => write!(out, "simple{} {{", at(off)),
+            Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd => write!(out, "}}"),
+            Inst::Set(v, off) => write!(out, "set {}{}", v, at(off)),
+            Inst::Scan(stride) => write!(out, "scan {}", stride),
+            Inst::Debug => write!(out, "debug"),
+            Inst::ProcStart => write!(out, "proc {{"),
+            Inst::Call => write!(out, "call"),
+            Inst::Exit => write!(out, "exit"),
+            Inst::Save => write!(out, "save"),
+            Inst::Restore => write!(out, "restore"),
+            Inst::Bitwise(op) => write!(out, "{}", bit_op_name(op)),
+        };
+        out.push('\n');
+        if matches!(
+            ins,
+            Inst::LoopStart | Inst::SimpleLoopStart(_) | Inst::ProcStart
+        ) {
+            loop_nest += 1;
+        }
+    }
+
+    out
+}
+
+const BIT_OPS: [(BitOp, &str); 6] = [
+    (BitOp::Shl, "shl"),
+    (BitOp::Shr, "shr"),
+    (BitOp::Not, "not"),
+    (BitOp::Xor, "xor"),
+    (BitOp::And, "and"),
+    (BitOp::Or, "or"),
+];
+
+fn bit_op_name(op: BitOp) -> &'static str {
+    BIT_OPS.iter().find(|(o, _)| *o == op).unwrap().1
+}
+
+// The word `print_ir` starts `ins` with, `}` for the ends of blocks.
+pub fn inst_name(ins: &Inst) -> &'static str {
+    match ins {
+        Inst::Add(..) => "add",
+        Inst::AddFrom(..) => "addfrom",
+        Inst::SubFrom(..) => "subfrom",
+        Inst::Left(_) => "left",
+        Inst::Right(_) => "right",
+        Inst::In => "in",
+        Inst::Out => "out",
+        Inst::LoopStart => "loop",
+        Inst::SimpleLoopStart(_) => "simple",
+        Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd => "}",
+        Inst::Set(..) => "set",
+        Inst::Scan(_) => "scan",
+        Inst::Debug => "debug",
+        Inst::ProcStart => "proc",
+        Inst::Call => "call",
+        Inst::Exit => "exit",
+        Inst::Save => "save",
+        Inst::Restore => "restore",
+        Inst::Bitwise(op) => bit_op_name(*op),
+    }
+}
+
+// The IR after each stage of the pipeline as a JSON array of objects with
+// the stage, the number of instructions, how many there are of each kind
+// but the ends of blocks, and the IR in its textual form.
+pub fn stages_to_json(stages: &[(&str, IR)]) -> String {
+    let mut out = String::from("[");
+    for (idx, (stage, ir)) in stages.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"pass\":\"{}\",\"instructions\":{},\"counts\":{{",
+            stage,
+            ir.len()
+        );
+        // Kinds in order of first appearance.
+        let mut counts: Vec<(&str, usize)> = vec![];
+        for name in ir.iter().map(inst_name).filter(|name| *name != "}") {
+            match counts.iter_mut().find(|(kind, _)| *kind == name) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((name, 1)),
+            }
+        }
+        for (idx, (kind, count)) in counts.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":{}", kind, count);
+        }
+        out.push_str("},\"ir\":");
+        json_string(&mut out, &print_ir(ir));
+        out.push('}');
+    }
+    out.push(']');
+
+    out
+}
+
+fn at(off: i32) -> String {
+    match off {
+        0 => String::new(),
+        off => format!(" @{}", off),
+    }
+}
+
+pub fn parse_ir(text: &str) -> Result<IR, TextError> {
+    let mut ir: IR = vec![];
+    // The instruction that closes each open brace.
+    let mut ends: Vec<Inst> = vec![];
+
+    for (idx, line) in text.lines().enumerate() {
+        let err = |message: String| TextError {
+            line: idx + 1,
+            message,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        let opens = words.last() == Some(&"{");
+        if opens {
+            words.pop();
+        }
+        let mut off = None;
+        if let Some(word) = words.last().filter(|word| word.starts_with('@')) {
+            off = Some(
+                word[1..]
+                    .parse()
+                    .map_err(|_| err(format!("bad offset `{}`", word)))?,
+            );
+            words.pop();
+        }
+
+        let name = *words
+            .first()
+            .ok_or_else(|| err("missing instruction".to_string()))?;
+        let operand = || -> Result<i64, TextError> {
+            match words[1..] {
+                [word] => word
+                    .parse()
+                    .map_err(|_| err(format!("bad operand `{}`", word))),
+                _ => Err(err(format!("`{}` takes one operand", name))),
+            }
+        };
+        // Values are taken mod 256, so `add 255` and `add -1` are the same,
+        // but one that fits in a byte neither way is an error.
+        let value = || -> Result<i64, TextError> {
+            match operand()? {
+                value @ -128..=255 => Ok(value),
+                value => Err(err(format!("value {} does not fit in a cell", value))),
+            }
+        };
+        let count = || -> Result<usize, TextError> {
+            usize::try_from(operand()?).map_err(|_| err("count must not be negative".to_string()))
+        };
+        let no_operand = || -> Result<(), TextError> {
+            match words.len() {
+                1 => Ok(()),
+                _ => Err(err(format!("`{}` takes no operands", name))),
+            }
+        };
+        if off.is_some() && !matches!(name, "add" | "addfrom" | "subfrom" | "set" | "simple") {
+            return Err(err(format!("`{}` takes no offset", name)));
+        }
+        let off = off.unwrap_or(0);
+
+        let ins = match (name, opens) {
+            ("add", false) => Inst::Add(value()? as i8, off),
+            ("addfrom", false) => Inst::AddFrom(count()?, off),
+            ("subfrom", false) => Inst::SubFrom(count()?, off),
+            ("left", false) => Inst::Left(count()?),
+            ("right", false) => Inst::Right(count()?),
+            ("in", false) => no_operand().map(|_| Inst::In)?,
+            ("out", false) => no_operand().map(|_| Inst::Out)?,
+            ("debug", false) => no_operand().map(|_| Inst::Debug)?,
+            ("call", false) => no_operand().map(|_| Inst::Call)?,
+            ("exit", false) => no_operand().map(|_| Inst::Exit)?,
+            ("save", false) => no_operand().map(|_| Inst::Save)?,
+            ("restore", false) => no_operand().map(|_| Inst::Restore)?,
+            (name, false) if BIT_OPS.iter().any(|(_, n)| *n == name) => {
+                no_operand()?;
+                Inst::Bitwise(BIT_OPS.iter().find(|(_, n)| *n == name).unwrap().0)
+            }
+            ("set", false) => Inst::Set(value()? as u8, off),
+            ("scan", false) => Inst::Scan(
+                i32::try_from(operand()?)
+                    .map_err(|_| err("stride does not fit in an i32".to_string()))?,
+            ),
+            ("loop", true) => {
+                no_operand()?;
+                ends.push(Inst::LoopEnd);
+                Inst::LoopStart
+            }
+            ("simple", true) => {
+                no_operand()?;
+                ends.push(Inst::SimpleLoopEnd);
+                Inst::SimpleLoopStart(off)
+            }
+            ("proc", true) => {
+                no_operand()?;
+                ends.push(Inst::ProcEnd);
+                Inst::ProcStart
+            }
+            ("}", false) => {
+                no_operand()?;
+                ends.pop()
+                    .ok_or_else(|| err("`}` without an open block".to_string()))?
+            }
+            (word, _) => return Err(err(format!("unknown instruction `{}`", word))),
+        };
+        ir.push(ins);
+    }
+
+    if !ends.is_empty() {
+        return Err(TextError {
+            line: text.lines().count(),
+            message: "unclosed block at end of input".to_string(),
+        });
+    }
+
+    Ok(ir)
+}
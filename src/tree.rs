@@ -0,0 +1,196 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::ir::{Inst, Offset, IR};
+
+// Structured form of the IR. Loops and procedures own their bodies instead
+// of being delimited by `LoopStart`/`LoopEnd` markers, so a `Node::Inst`
+// never holds one of the marker instructions.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub enum Node {
+    Inst(Inst),
+    Loop(LoopId, Block),
+    SimpleLoop(LoopId, Offset, Block),
+    // A procedure definition, see `Inst::ProcStart`.
+    Proc(Block),
+}
+
+pub type Block = Vec<Node>;
+
+// Loops are numbered in the order they open in the flat IR they were built
+// from, which for source input is the order of their `[`. Passes keep the
+// number when they rebuild a loop, including when it becomes a simple loop.
+pub type LoopId = usize;
+
+// How deeply loops and procedures may nest in the IR a tree is built from.
+// The passes walk the tree recursively, and `licm` can add a level for each
+// loop it rewrites, so this leaves room for twice the depth within the
+// stack of a wasm build. Deeper input is an error rather than an overflow.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructureError {
+    // Index of the offending instruction in the flat IR.
+    UnmatchedStart(usize),
+    UnmatchedEnd(usize),
+    UnmatchedProcStart(usize),
+    UnmatchedProcEnd(usize),
+    NestedProc(usize),
+    TooDeep(usize),
+}
+
+impl fmt::Display for StructureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StructureError::UnmatchedStart(idx) => {
+                write!(f, "loop opened at instruction {} is never closed", idx)
+            }
+            StructureError::UnmatchedEnd(idx) => {
+                write!(f, "loop closed at instruction {} was never opened", idx)
+            }
+            StructureError::UnmatchedProcStart(idx) => {
+                write!(f, "procedure started at instruction {} is never ended", idx)
+            }
+            StructureError::UnmatchedProcEnd(idx) => {
+                write!(
+                    f,
+                    "procedure ended at instruction {} was never started",
+                    idx
+                )
+            }
+            StructureError::NestedProc(idx) => {
+                write!(
+                    f,
+                    "procedure started at instruction {} is inside another",
+                    idx
+                )
+            }
+            StructureError::TooDeep(idx) => {
+                write!(
+                    f,
+                    "loop or procedure started at instruction {} nests more than {} deep",
+                    idx, MAX_DEPTH
+                )
+            }
+        }
+    }
+}
+
+impl Error for StructureError {}
+
+// Checks `ir` is no deeper than `MAX_DEPTH`, ahead of building a tree from
+// it. The passes may nest the tree deeper, so this isn't part of `from_flat`.
+pub fn check_depth(ir: &IR) -> Result<(), StructureError> {
+    let mut depth: usize = 0;
+    for (idx, ins) in ir.iter().enumerate() {
+        match ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) | Inst::ProcStart => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(StructureError::TooDeep(idx));
+                }
+            }
+            Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+pub fn from_flat(ir: &IR) -> Result<Block, StructureError> {
+    // Each open loop or procedure keeps the index and instruction that
+    // opened it, its number and the block that was being built outside of it.
+    let mut stack: Vec<(usize, Inst, LoopId, Block)> = vec![];
+    let mut block: Block = vec![];
+    let mut next_id: LoopId = 0;
+    for (idx, ins) in ir.iter().enumerate() {
+        match *ins {
+            Inst::LoopStart | Inst::SimpleLoopStart(_) => {
+                stack.push((idx, *ins, next_id, std::mem::take(&mut block)));
+                next_id += 1;
+            }
+            Inst::ProcStart => {
+                if stack
+                    .iter()
+                    .any(|(_, start, _, _)| *start == Inst::ProcStart)
+                {
+                    return Err(StructureError::NestedProc(idx));
+                }
+                stack.push((idx, *ins, next_id, std::mem::take(&mut block)));
+            }
+            Inst::LoopEnd | Inst::SimpleLoopEnd | Inst::ProcEnd => {
+                let unmatched = match ins {
+                    Inst::ProcEnd => StructureError::UnmatchedProcEnd(idx),
+                    _ => StructureError::UnmatchedEnd(idx),
+                };
+                let (_, start, id, outer) = stack.pop().ok_or(unmatched)?;
+                let body = std::mem::replace(&mut block, outer);
+                match (start, ins) {
+                    (Inst::LoopStart, Inst::LoopEnd) => block.push(Node::Loop(id, body)),
+                    (Inst::SimpleLoopStart(off), Inst::SimpleLoopEnd) => {
+                        block.push(Node::SimpleLoop(id, off, body))
+                    }
+                    (Inst::ProcStart, Inst::ProcEnd) => block.push(Node::Proc(body)),
+                    _ => return Err(unmatched),
+                }
+            }
+            _ => block.push(Node::Inst(*ins)),
+        }
+    }
+
+    match stack.pop() {
+        Some((idx, Inst::ProcStart, _, _)) => Err(StructureError::UnmatchedProcStart(idx)),
+        Some((idx, _, _, _)) => Err(StructureError::UnmatchedStart(idx)),
+        None => Ok(block),
+    }
+}
+
+pub fn to_flat(block: &[Node]) -> IR {
+    let mut ir: IR = vec![];
+    flatten_into(block, &mut ir);
+    ir
+}
+
+fn flatten_into(block: &[Node], ir: &mut IR) {
+    for node in block {
+        match node {
+            Node::Inst(ins) => ir.push(*ins),
+            Node::Loop(_, body) => {
+                ir.push(Inst::LoopStart);
+                flatten_into(body, ir);
+                ir.push(Inst::LoopEnd);
+            }
+            Node::SimpleLoop(_, off, body) => {
+                ir.push(Inst::SimpleLoopStart(*off));
+                flatten_into(body, ir);
+                ir.push(Inst::SimpleLoopEnd);
+            }
+            Node::Proc(body) => {
+                ir.push(Inst::ProcStart);
+                flatten_into(body, ir);
+                ir.push(Inst::ProcEnd);
+            }
+        }
+    }
+}
+
+// Numbers of the loops in `block` in the order their start markers appear
+// in `to_flat(block)`.
+pub fn loop_ids(block: &[Node]) -> Vec<LoopId> {
+    let mut ids = vec![];
+    collect_ids(block, &mut ids);
+    ids
+}
+
+fn collect_ids(block: &[Node], ids: &mut Vec<LoopId>) {
+    for node in block {
+        match node {
+            Node::Inst(_) => (),
+            Node::Loop(id, body) | Node::SimpleLoop(id, _, body) => {
+                ids.push(*id);
+                collect_ids(body, ids);
+            }
+            Node::Proc(body) => collect_ids(body, ids),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use bf_wasm_compiler::debugger::{origins, Debugger, Origin, Reply};
+use bf_wasm_compiler::{optimize_for_debugger, CompileOptions, Optimized};
+
+// Breakpoint 0 sits inside the first straight-line run, breakpoint 1 at the
+// end of the second line.
+const PROGRAM: &str = "++>#+<.\n>+++#\n.";
+
+fn optimized(source: &str) -> Optimized {
+    optimize_for_debugger(source, &CompileOptions::default()).unwrap()
+}
+
+fn execute(debugger: &mut Debugger, line: &str) -> String {
+    match debugger.execute(line) {
+        Reply::Text(text) => text,
+        Reply::Quit => panic!("`{}` quit the debugger", line),
+    }
+}
+
+#[test]
+fn origins_per_command() {
+    let command = |idx| Origin {
+        first: idx,
+        last: idx,
+    };
+    let plain = origins("+>[-<]+.", &optimized("+>[-<]+."));
+    assert_eq!(plain, (0..8).map(command).collect::<Vec<_>>());
+
+    // `+[-]+` becomes a single store, made from the whole run.
+    let opts = CompileOptions {
+        cell_zero_opt: true,
+        ..CompileOptions::default()
+    };
+    let source = "+[-]+.";
+    let optimized = optimize_for_debugger(source, &opts).unwrap();
+    let zeroed = origins(source, &optimized);
+    assert_eq!(zeroed, [Origin { first: 0, last: 4 }, command(5)]);
+}
+
+#[test]
+fn steps() {
+    let optimized = optimized(PROGRAM);
+    let mut debugger = Debugger::new(PROGRAM, &optimized);
+    assert!(execute(&mut debugger, "s").ends_with("ir 1: add 1\n"));
+    assert!(execute(&mut debugger, "step 2").ends_with("ir 3: add 1\n"));
+    assert!(execute(&mut debugger, "si").ends_with("ir 4: left 1\n"));
+    // An empty line repeats the last step.
+    assert!(execute(&mut debugger, "").ends_with("ir 5: out\n"));
+    assert!(execute(&mut debugger, "w").starts_with("    1:7 "));
+    assert_eq!(debugger.take_output(), b"");
+    execute(&mut debugger, "s");
+    assert_eq!(debugger.take_output(), [2]);
+}
+
+#[test]
+fn breakpoints() {
+    let program = optimized(PROGRAM);
+    let mut debugger = Debugger::new(PROGRAM, &program);
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 0, `#` before 1:5\n"));
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 1, `#` before 3:1\n"));
+    assert_eq!(execute(&mut debugger, "c"), "program finished on cell 1");
+
+    // A `#` before the first command stops the program before it runs.
+    let source = "#+>#+";
+    let marked = optimized(source);
+    let mut debugger = Debugger::new(source, &marked);
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 0, "));
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 1, "));
+
+    let mut debugger = Debugger::new(PROGRAM, &program);
+    assert_eq!(execute(&mut debugger, "d 0"), "deleted breakpoint 0");
+    assert_eq!(execute(&mut debugger, "b 2"), "breakpoint 2 at line 2");
+    assert_eq!(execute(&mut debugger, "b 4"), "no commands on line 4");
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 2, line 2\n"));
+    assert!(execute(&mut debugger, "c").starts_with("breakpoint 1, "));
+}
+
+#[test]
+fn watchpoints() {
+    let source = "+>+++<+>-";
+    let optimized = optimized(source);
+    let mut debugger = Debugger::new(source, &optimized);
+    assert_eq!(execute(&mut debugger, "watch 1"), "watching cell 1");
+    assert!(execute(&mut debugger, "c").starts_with("cell 1 changed from 0 to 1\n"));
+    assert!(execute(&mut debugger, "c").starts_with("cell 1 changed from 1 to 2\n"));
+    assert_eq!(
+        execute(&mut debugger, "unwatch 1"),
+        "stopped watching cell 1"
+    );
+    assert_eq!(execute(&mut debugger, "c"), "program finished on cell 1");
+}
@@ -0,0 +1,88 @@
+use bf_wasm_compiler::dot::to_dot;
+use bf_wasm_compiler::frontend::{Dialect, ParseOptions};
+use bf_wasm_compiler::tree::from_flat;
+use bf_wasm_compiler::{optimize, CompileOptions};
+
+fn dot(source: &str, opts: &CompileOptions) -> String {
+    to_dot(&from_flat(&optimize(source, opts).unwrap()).unwrap())
+}
+
+#[test]
+fn loops_and_blocks() {
+    let opts = CompileOptions {
+        cell_zero_opt: true,
+        loop_opt: true,
+        scan_opt: true,
+        ..CompileOptions::default()
+    };
+    let expected = r#"digraph ir {
+    node [shape=box, fontname=monospace];
+    n0 [label="entry", shape=oval];
+    n1 [label="set 0\ladd 1 @1\lright 1\l", style=filled, fillcolor=lightyellow];
+    subgraph cluster_2 {
+        label="simple loop (body runs at most once)";
+        color=darkgreen;
+        n3 [label="test cell 0", shape=diamond];
+        n4 [label="addfrom 1 @1\lset 0\l", style=filled, fillcolor=lightyellow];
+    }
+    n5 [label="left 1\l"];
+    n6 [label="scan -1\l", style=filled, fillcolor=lightblue];
+    n7 [label="in\l"];
+    subgraph cluster_8 {
+        label="loop: performs I/O";
+        color=red;
+        n9 [label="test cell", shape=diamond];
+        n10 [label="out\lin\l"];
+    }
+    n11 [label="exit", shape=oval];
+    n0 -> n1 [label=""];
+    n1 -> n3 [label=""];
+    n3 -> n4 [label="nonzero"];
+    n4 -> n5 [label=""];
+    n3 -> n5 [label="zero"];
+    n5 -> n6 [label=""];
+    n6 -> n7 [label=""];
+    n7 -> n9 [label=""];
+    n9 -> n10 [label="nonzero"];
+    n10 -> n9 [label=""];
+    n9 -> n11 [label="zero"];
+}
+"#;
+    assert_eq!(dot("+[-]>+[->+<]<[<],[.,]", &opts), expected);
+}
+
+#[test]
+fn procedures() {
+    let opts = CompileOptions {
+        parse: ParseOptions {
+            dialect: Dialect::Pbrain,
+            ..ParseOptions::default()
+        },
+        ..CompileOptions::default()
+    };
+    // The body hangs off its own entry, while the program runs on past the
+    // definition to the call.
+    let expected = r#"digraph ir {
+    node [shape=box, fontname=monospace];
+    n0 [label="entry", shape=oval];
+    n1 [label="add 1\l"];
+    subgraph cluster_2 {
+        label="procedure (body runs when called)";
+        color=purple;
+        n3 [label="define procedure cell"];
+        n4 [label="called", shape=oval];
+        n5 [label="add 1 @1\lright 1\lout\lleft 1\l"];
+        n6 [label="return", shape=oval];
+    }
+    n7 [label="call\l", style=filled, fillcolor=lightblue];
+    n8 [label="exit", shape=oval];
+    n0 -> n1 [label=""];
+    n1 -> n3 [label=""];
+    n4 -> n5 [label=""];
+    n5 -> n6 [label=""];
+    n3 -> n7 [label=""];
+    n7 -> n8 [label=""];
+}
+"#;
+    assert_eq!(dot("+(>+.<):", &opts), expected);
+}
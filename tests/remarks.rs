@@ -0,0 +1,79 @@
+use bf_wasm_compiler::remarks::{to_json, Remark, RemarkKind, Span};
+use bf_wasm_compiler::{optimize_ir, optimize_with, CompileOptions};
+
+fn all_passes() -> CompileOptions {
+    CompileOptions {
+        cell_zero_opt: true,
+        loop_opt: true,
+        licm: true,
+        scan_opt: true,
+        ..CompileOptions::default()
+    }
+}
+
+fn remarks(source: &str) -> Vec<Remark> {
+    optimize_with(source, &all_passes(), &mut |_, _| ())
+        .unwrap()
+        .remarks
+}
+
+#[test]
+fn reasons_and_spans() {
+    let printed: Vec<String> = remarks("+[->+<]\n>[-]<,[.\n,]\n+[->>+<-]")
+        .iter()
+        .map(Remark::to_string)
+        .collect();
+    assert_eq!(
+        printed,
+        [
+            "2:2-2:4: zero: applied: replaced with `set 0`",
+            "1:2-1:7: loops: applied: converted to a simple loop",
+            "2:7-3:2: loops: missed: performs I/O",
+            "4:2-4:9: loops: missed: moves the pointer by 1 per trip",
+            "4:2-4:9: scan: missed: touches cells besides the one it tests",
+        ]
+    );
+}
+
+#[test]
+fn json() {
+    let remarks = remarks("+[-]\n,[.,]");
+    assert_eq!(
+        remarks[0],
+        Remark {
+            pass: "zero",
+            kind: RemarkKind::Applied,
+            loop_id: 0,
+            span: Some(Span {
+                start: (1, 2),
+                end: (1, 4),
+            }),
+            message: "replaced with `set 0`".to_string(),
+        }
+    );
+    assert_eq!(
+        to_json(&remarks),
+        concat!(
+            r#"[{"pass":"zero","kind":"applied","loop":0,"#,
+            r#""span":{"start":{"line":1,"column":2},"end":{"line":1,"column":4}},"#,
+            r#""message":"replaced with `set 0`"},"#,
+            r#"{"pass":"loops","kind":"missed","loop":1,"#,
+            r#""span":{"start":{"line":2,"column":2},"end":{"line":2,"column":5}},"#,
+            r#""message":"performs I/O"}]"#,
+        )
+    );
+
+    // IR read back from text has no source to point into.
+    let remark = Remark::missed("loops", 3, "says \"no\"".to_string());
+    assert_eq!(remark.to_string(), "loop 3: loops: missed: says \"no\"");
+    assert_eq!(
+        to_json(&[remark]),
+        r#"[{"pass":"loops","kind":"missed","loop":3,"span":null,"message":"says \"no\""}]"#
+    );
+
+    let ir = optimize_with(",[.,]", &CompileOptions::default(), &mut |_, _| ())
+        .unwrap()
+        .ir;
+    let optimized = optimize_ir(&ir, &all_passes(), &mut |_, _| ()).unwrap();
+    assert_eq!(optimized.remarks[0].span, None);
+}
@@ -0,0 +1,245 @@
+use bf_wasm_compiler::backend::{BackendOptions, Fuel, FuelBudget, OutOfFuel};
+use bf_wasm_compiler::frontend::{parse_with, Dialect, ParseOptions};
+use bf_wasm_compiler::interp::{Machine, Outcome};
+use bf_wasm_compiler::runtime::{run_program, Exit, Output, RunError};
+use bf_wasm_compiler::tree::{StructureError, MAX_DEPTH};
+use bf_wasm_compiler::{optimize, CompileError, CompileOptions};
+
+const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+// Prints the digits, leaving markers for the scans to find on the way back.
+const DIGITS: &str = "++++++++[>++++++<-]>>++++++++++[<.+>>+>>+<<<-]>>[>>]<<[<<]<.";
+const ECHO: &str = ",[.,]";
+
+// The pass and backend combinations every program is run with.
+fn option_sets() -> Vec<CompileOptions> {
+    let backends = [
+        BackendOptions::default(),
+        BackendOptions {
+            reg_cache: true,
+            ..BackendOptions::default()
+        },
+        BackendOptions {
+            resumable: true,
+            profile: true,
+            ..BackendOptions::default()
+        },
+        BackendOptions {
+            inspect: true,
+            fuel: Some(Fuel {
+                budget: FuelBudget::Param,
+                out_of_fuel: OutOfFuel::Import,
+            }),
+            ..BackendOptions::default()
+        },
+        BackendOptions {
+            outline: Some(2),
+            ..BackendOptions::default()
+        },
+        BackendOptions {
+            reg_cache: true,
+            outline: Some(2),
+            dp_global: true,
+            ..BackendOptions::default()
+        },
+    ];
+    let mut sets = vec![];
+    for optimize in [false, true] {
+        for backend in &backends {
+            sets.push(CompileOptions {
+                cell_zero_opt: optimize,
+                loop_opt: optimize,
+                licm: optimize,
+                scan_opt: optimize,
+                backend: backend.clone(),
+                ..CompileOptions::default()
+            });
+        }
+    }
+    sets.push(CompileOptions {
+        precompute: Some(1_000_000),
+        ..CompileOptions::default()
+    });
+    sets
+}
+
+// Runs `source` on the interpreter, which reads zero once input runs out
+// like the runtime does.
+fn interpret(source: &str, parse: &ParseOptions, input: &[u8]) -> Output {
+    let ir = parse_with(source, parse);
+    let mut machine = Machine::new(&ir);
+    machine.input.extend(input);
+    machine.input_closed = true;
+    assert_eq!(machine.run(usize::MAX), Outcome::Finished);
+    Output {
+        exit: Exit::Finished {
+            cell: machine.dp as i32 - 16,
+            value: machine.tape[machine.dp],
+        },
+        output: machine.output,
+    }
+}
+
+fn check(source: &str, parse: ParseOptions, input: &[u8]) {
+    let expected = interpret(source, &parse, input);
+    for opts in option_sets() {
+        let opts = CompileOptions { parse, ..opts };
+        let got = run_program(source, &opts, input).unwrap();
+        assert_eq!(got, expected, "with {:?}", opts);
+    }
+}
+
+#[test]
+fn hello_world() {
+    check(HELLO, ParseOptions::default(), b"");
+    let output = run_program(HELLO, &CompileOptions::default(), b"").unwrap();
+    assert_eq!(output.output, b"Hello World!\n");
+}
+
+#[test]
+fn scans() {
+    check(DIGITS, ParseOptions::default(), b"");
+}
+
+#[test]
+fn inner_loop_overwrites_an_add() {
+    // The outer loop adds to the cell the converted inner loop clears, so
+    // it can't become a simple loop, which would add before clearing.
+    let source = "+++>+<[->>+<[->[-]<]<]>>.";
+    check(source, ParseOptions::default(), b"");
+    let opts = CompileOptions {
+        cell_zero_opt: true,
+        loop_opt: true,
+        ..CompileOptions::default()
+    };
+    assert_eq!(run_program(source, &opts, b"").unwrap().output, [2]);
+}
+
+#[test]
+fn copy_to_many_cells() {
+    // The loop adds its counter to more cells than the register cache
+    // holds, so caching the targets evicts the others.
+    for targets in [16, 17] {
+        let source = format!(
+            "+++[-{}{}]{}",
+            ">+".repeat(targets),
+            "<".repeat(targets),
+            ">.".repeat(targets)
+        );
+        check(&source, ParseOptions::default(), b"");
+    }
+}
+
+#[test]
+fn input() {
+    check(ECHO, ParseOptions::default(), b"echo me");
+    // Resume points in sibling and nested loops, so that every loop's
+    // `br_table` covers only some of them.
+    check(
+        ",.>,[.,[.,]>,[.,]<]>,.",
+        ParseOptions::default(),
+        b"abc\0de\0f\0\0gh",
+    );
+    let output = run_program(ECHO, &CompileOptions::default(), b"abc").unwrap();
+    assert_eq!(output.output, b"abc");
+}
+
+#[test]
+fn inline_input() {
+    let parse = ParseOptions {
+        inline_input: true,
+        ..ParseOptions::default()
+    };
+    let opts = CompileOptions {
+        parse,
+        ..CompileOptions::default()
+    };
+    let output = run_program(",[.,]!inline", &opts, b"ignored").unwrap();
+    assert_eq!(output.output, b"inline");
+    // The input starts after the `!` of `Ook. Ook!`, which reads a byte,
+    // and `Ook!` on its own.
+    let ook = CompileOptions {
+        parse: ParseOptions {
+            dialect: Dialect::Ook,
+            ..parse
+        },
+        ..opts
+    };
+    let output = run_program("Ook. Ook! Ook! Ook. Ook! ok", &ook, b"").unwrap();
+    assert_eq!(output.output, b" ");
+}
+
+#[test]
+fn dialects() {
+    let pbrain = ParseOptions {
+        dialect: Dialect::Pbrain,
+        ..ParseOptions::default()
+    };
+    // Procedure 1 bumps and prints the cell after its number, which is
+    // set to 64 before calling it three times.
+    check("+(>+.<)>>++++++++[<++++++++>-]<<:::", pbrain, b"");
+    let extended = ParseOptions {
+        dialect: Dialect::Extended,
+        ..ParseOptions::default()
+    };
+    check("+++++++[>++++++++++<-]>-$.+!.@+.", extended, b"");
+}
+
+#[test]
+fn out_of_fuel() {
+    let fuel = |out_of_fuel| CompileOptions {
+        backend: BackendOptions {
+            fuel: Some(Fuel {
+                budget: FuelBudget::Fixed(100),
+                out_of_fuel,
+            }),
+            ..BackendOptions::default()
+        },
+        ..CompileOptions::default()
+    };
+    let output = run_program("+.[]", &fuel(OutOfFuel::Import), b"").unwrap();
+    assert_eq!(output.output, [1]);
+    assert_eq!(output.exit, Exit::OutOfFuel);
+    assert!(matches!(
+        run_program("+.[]", &fuel(OutOfFuel::Trap), b""),
+        Err(RunError::Trap(_))
+    ));
+}
+
+#[test]
+fn debug_dumps() {
+    let opts = CompileOptions {
+        parse: ParseOptions {
+            debug: true,
+            ..ParseOptions::default()
+        },
+        ..CompileOptions::default()
+    };
+    let output = run_program("+++>++#", &opts, b"").unwrap();
+    let dump = String::from_utf8(output.output).unwrap();
+    assert!(dump.starts_with("\n# cell 1\n"), "{}", dump);
+    assert!(dump.contains("   3 [  2]"), "{}", dump);
+}
+
+#[test]
+fn pointer_off_the_tape() {
+    assert!(matches!(
+        run_program("<<<<<<<<<<<<<<<<<<<<+", &CompileOptions::default(), b""),
+        Err(RunError::Trap(_))
+    ));
+}
+
+#[test]
+fn deep_nesting() {
+    // Every level stores to a cell nothing else touches, so `licm` wraps
+    // each loop in another and nests the tree twice as deep. The `[-]` at
+    // the bottom is the last level.
+    let nest = |depth| {
+        let levels = depth - 1;
+        format!("+{}{}", "[<[-]>>".repeat(levels), "<-]".repeat(levels))
+    };
+    check(&nest(MAX_DEPTH), ParseOptions::default(), b"");
+    assert!(matches!(
+        optimize(&nest(MAX_DEPTH + 1), &CompileOptions::default()),
+        Err(CompileError::Structure(StructureError::TooDeep(_)))
+    ));
+}
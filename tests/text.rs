@@ -0,0 +1,85 @@
+use bf_wasm_compiler::frontend::{parse_with, Dialect, ParseOptions};
+use bf_wasm_compiler::ir::{BitOp, Inst};
+use bf_wasm_compiler::text::{parse_ir, print_ir, TextError};
+use bf_wasm_compiler::{optimize, CompileOptions};
+
+#[test]
+fn round_trip() {
+    let ir = vec![
+        Inst::Add(-1, 2),
+        Inst::Set(0, 0),
+        Inst::AddFrom(3, -1),
+        Inst::SubFrom(1, 4),
+        Inst::LoopStart,
+        Inst::Right(2),
+        Inst::SimpleLoopStart(1),
+        Inst::Left(1),
+        Inst::SimpleLoopEnd,
+        Inst::LoopEnd,
+        Inst::Scan(-2),
+        Inst::ProcStart,
+        Inst::Bitwise(BitOp::Xor),
+        Inst::Bitwise(BitOp::Shl),
+        Inst::ProcEnd,
+        Inst::Call,
+        Inst::In,
+        Inst::Out,
+        Inst::Debug,
+        Inst::Save,
+        Inst::Restore,
+        Inst::Exit,
+    ];
+    let text = print_ir(&ir);
+    assert!(
+        text.contains("\n    simple @1 {\n        left 1\n    }\n"),
+        "{}",
+        text
+    );
+    assert!(text.contains("\nproc {\n    xor\n    shl\n}\n"), "{}", text);
+    assert_eq!(parse_ir(&text), Ok(ir));
+
+    // Optimized programs come back as they were printed.
+    let opts = CompileOptions {
+        cell_zero_opt: true,
+        loop_opt: true,
+        licm: true,
+        scan_opt: true,
+        ..CompileOptions::default()
+    };
+    let ir = optimize("+[->>+++<<]>>[-]<<+[<]+[[>]>++<<-]", &opts).unwrap();
+    assert_eq!(parse_ir(&print_ir(&ir)), Ok(ir));
+    let pbrain = ParseOptions {
+        dialect: Dialect::Pbrain,
+        ..ParseOptions::default()
+    };
+    let ir = parse_with("+(>+.<):", &pbrain);
+    assert_eq!(parse_ir(&print_ir(&ir)), Ok(ir));
+}
+
+#[test]
+fn comments_and_defaults() {
+    let text = "# a comment\n\nadd 255   # the same as add -1\nset 7 @-3\nscan 1\n";
+    assert_eq!(
+        parse_ir(text),
+        Ok(vec![Inst::Add(-1, 0), Inst::Set(7, -3), Inst::Scan(1)])
+    );
+}
+
+#[test]
+fn errors() {
+    let error = |text: &str| parse_ir(text).unwrap_err();
+    let at = |line, message: &str| TextError {
+        line,
+        message: message.to_string(),
+    };
+    assert_eq!(error("add 1\nfrob"), at(2, "unknown instruction `frob`"));
+    assert_eq!(error("out 1"), at(1, "`out` takes no operands"));
+    assert_eq!(error("left 1 @2"), at(1, "`left` takes no offset"));
+    assert_eq!(error("right -1"), at(1, "count must not be negative"));
+    assert_eq!(error("add 1 @x"), at(1, "bad offset `@x`"));
+    assert_eq!(error("}"), at(1, "`}` without an open block"));
+    assert_eq!(
+        error("loop {\nadd 1"),
+        at(2, "unclosed block at end of input")
+    );
+}
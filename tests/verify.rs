@@ -0,0 +1,79 @@
+use bf_wasm_compiler::ir::{verify, Inst, VerifyError};
+use bf_wasm_compiler::text::parse_ir;
+use bf_wasm_compiler::{optimize_ir, CompileError, CompileOptions};
+
+fn error(ir: &[Inst]) -> (usize, String) {
+    let VerifyError { index, message, .. } = verify(&ir.to_vec()).unwrap_err();
+    (index, message)
+}
+
+#[test]
+fn well_formed() {
+    let ir = parse_ir("loop {\n  simple @1 {\n  }\n}\nproc {\n  scan -2\n}\nexit\n").unwrap();
+    assert_eq!(verify(&ir), Ok(()));
+}
+
+#[test]
+fn unpaired_blocks() {
+    let message = |index, message: &str| (index, message.to_string());
+    assert_eq!(
+        error(&[Inst::Add(1, 0), Inst::LoopEnd]),
+        message(1, "no loop to close")
+    );
+    assert_eq!(
+        error(&[Inst::ProcStart, Inst::LoopEnd]),
+        message(1, "closes a procedure")
+    );
+    assert_eq!(
+        error(&[Inst::LoopStart, Inst::SimpleLoopEnd]),
+        message(1, "closes a non-simple loop")
+    );
+    assert_eq!(
+        error(&[Inst::SimpleLoopStart(0), Inst::LoopEnd]),
+        message(1, "closes a simple loop")
+    );
+    assert_eq!(
+        error(&[
+            Inst::LoopStart,
+            Inst::SimpleLoopStart(2),
+            Inst::SimpleLoopEnd
+        ]),
+        message(0, "loop is never closed")
+    );
+    assert_eq!(
+        error(&[Inst::ProcStart, Inst::ProcStart]),
+        message(1, "procedures can't be defined inside procedures")
+    );
+    assert_eq!(
+        error(&[Inst::ProcStart, Inst::Exit, Inst::ProcEnd]),
+        message(1, "exit can only end the program outside of procedures")
+    );
+}
+
+#[test]
+fn operands() {
+    assert_eq!(
+        error(&[Inst::Scan(3)]).1,
+        "scan stride must be one of 1, 2, 4, -1 or -2"
+    );
+    assert_eq!(
+        error(&[Inst::Right(1 << 31)]).1,
+        "count does not fit in an i32"
+    );
+
+    // Text that doesn't fit the instructions is rejected while parsing,
+    // and what does fit is verified before compiling.
+    assert_eq!(
+        parse_ir("add 300").unwrap_err().message,
+        "value 300 does not fit in a cell"
+    );
+    assert_eq!(
+        parse_ir("scan 4294967297").unwrap_err().message,
+        "stride does not fit in an i32"
+    );
+    let ir = parse_ir("scan 3").unwrap();
+    assert!(matches!(
+        optimize_ir(&ir, &CompileOptions::default(), &mut |_, _| ()),
+        Err(CompileError::InvalidIr { pass: "input", .. })
+    ));
+}
@@ -7,19 +7,92 @@ function getChar() {
   return inputBuffer[0]
 }
 
+let wasmExports
+
+// Formats cells starting at `first`, 16 to a line, with the cell at `dp` in
+// brackets. Cells are numbered from the cell the program starts on.
+function formatCells(cells, first, dp) {
+  let out = ''
+  for (let row = 0; row < cells.length; row += 16) {
+    out += `${first + row}:`.padStart(7)
+    for (let cell = row; cell < Math.min(row + 16, cells.length); cell++) {
+      const val = `${cells[cell]}`.padStart(3)
+      out += first + cell === dp ? `[${val}]` : ` ${val} `
+    }
+    out += '\n'
+  }
+  return out
+}
+
 const imports = {
   env: {
     debug_terminate: (cell_num, val) => console.log(`\nprogram terminated on cell: ${cell_num - 16} with value: ${val}`),
     write: x => process.stdout.write(String.fromCharCode(x)),
     read: () => getChar(),
+    out_of_fuel: () => console.log('\nprogram ran out of fuel'),
+    // Programs built with --debug-hash call this at every `#` with the
+    // address of the current cell. Like the native runtime, the dump starts
+    // no further left than the cell the program starts on.
+    debug: addr => {
+      const start = Math.max(16, addr - 8)
+      const cells = new Uint8Array(wasmExports.memory.buffer, start, 16)
+      process.stdout.write(`\n# cell ${addr - 16}\n${formatCells(cells, start - 16, addr - 16)}`)
+    },
   }
 };
 
+// Programs built with --profile count loop trips and I/O. Each site is named
+// in a custom section and saved with its counters, one site per line, for
+// `bf-wasm-compiler --profile-report` and `--profile-use`.
+function saveProfile(module, exports) {
+  const [section] = WebAssembly.Module.customSections(module, 'bf-profile-sites')
+  const names = new TextDecoder().decode(section).split('\n')
+  const base = exports.dump_profile()
+  const memory = exports.memory.buffer
+  const sites = new Int32Array(memory, base, 1)[0]
+  const counters = new BigInt64Array(memory, base + 16, sites * 2)
+  let lines = ''
+  for (let i = 0; i < sites; i++) {
+    lines += `${names[i]} ${counters[2 * i]} ${counters[2 * i + 1]}\n`
+  }
+  fs.writeFileSync('./rust_prog.profile', lines)
+  console.log('profile saved to rust_prog.profile')
+}
+
+// Formats `len` cells from cell `start` of a module built with --inspect,
+// 16 to a line, with the cell under the data pointer in brackets.
+function dumpTape(exports, start, len) {
+  const end = Math.min(start + len, exports.tape_len())
+  const cells = []
+  for (let cell = start; cell < end; cell++) {
+    cells.push(exports.get_cell(cell))
+  }
+  return formatCells(cells, start, exports.get_dp())
+}
+
 const wasmBuffer = fs.readFileSync('./rust_prog.wasm')
 WebAssembly.instantiate(wasmBuffer, imports).then(
   results => {
+    wasmExports = results.instance.exports
     console.time("wasm-run-time")
-    results.instance.exports.main();
+    const { main, resume } = results.instance.exports
+    // Programs built with `--fuel param` take their budget of loop trips.
+    let status = main.length === 1 ? main(BigInt(process.env.BF_FUEL || '1000000000')) : main()
+    // Programs built with --resumable return 1 whenever they need a byte of
+    // input, which is passed to `resume`.
+    while (resume && status === 1) {
+      status = resume(getChar())
+    }
     console.timeEnd("wasm-run-time")
+    // BF_TAPE=start,len picks the cells to show, by default the two rows
+    // around the data pointer.
+    if (results.instance.exports.get_dp) {
+      const dp = results.instance.exports.get_dp()
+      const [start, len] = (process.env.BF_TAPE || `${Math.max(0, dp - dp % 16)},32`).split(',').map(Number)
+      process.stdout.write(dumpTape(results.instance.exports, start, len))
+    }
+    if (results.instance.exports.dump_profile) {
+      saveProfile(results.module, results.instance.exports)
+    }
   }
 )